@@ -3,7 +3,6 @@ use std::fs::File;
 use std::io::stdin;
 use std::path::PathBuf;
 use serde_yaml::Value;
-use json_canon;
 
 #[derive(Parser)]
 struct Args {
@@ -0,0 +1,219 @@
+use base64::Engine;
+use serde_yaml::Value;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Files can only be embedded from under this directory
+const FILES_DIRECTORY: &str = "configuration/files";
+
+fn resolve_path(files_directory: &Path, path: &str) -> PathBuf {
+    let relative = Path::new(path);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        panic!("Embedded file path \"{path}\" must be relative, and stay within {FILES_DIRECTORY}/")
+    }
+    let full_path = files_directory.join(relative);
+
+    // and no escaping via symlinks either
+    let base = fs::canonicalize(files_directory)
+        .unwrap_or_else(|err| panic!("Can't embed \"{path}\" as {FILES_DIRECTORY}/ isn't accessible: {err}"));
+    let canonical = fs::canonicalize(&full_path)
+        .unwrap_or_else(|err| panic!("Failed to read embedded file {full_path:?}: {err}"));
+    if !canonical.starts_with(&base) {
+        panic!("Embedded file \"{path}\" resolves to {canonical:?}, outside of {FILES_DIRECTORY}/")
+    }
+    canonical
+}
+
+fn read(files_directory: &Path, path: &str) -> Vec<u8> {
+    let full_path = resolve_path(files_directory, path);
+    fs::read(&full_path).unwrap_or_else(|err| panic!("Failed to read embedded file {full_path:?}: {err}"))
+}
+
+fn read_text(files_directory: &Path, path: &str, tag: &str) -> String {
+    String::from_utf8(read(files_directory, path))
+        .unwrap_or_else(|_| panic!("{tag} file \"{path}\" isn't UTF-8 text (try !base64file)"))
+}
+
+// One or more `-----BEGIN X-----` / `-----END X-----` blocks of valid base64 (optionally with RFC 1421 headers)
+fn validate_pem(text: &str) -> Result<(), String> {
+    let mut lines = text.lines().map(str::trim_end);
+    let mut blocks = 0;
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let label = line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|rest| rest.strip_suffix("-----"))
+            .ok_or_else(|| format!("expected a BEGIN line, got \"{line}\""))?;
+        let end_line = format!("-----END {label}-----");
+        let mut body = String::new();
+        loop {
+            let line = lines
+                .next()
+                .ok_or_else(|| format!("missing \"{end_line}\""))?;
+            if line == end_line {
+                break;
+            }
+            if !line.contains(':') && !line.is_empty() {
+                body.push_str(line.trim());
+            }
+        }
+        base64::engine::general_purpose::STANDARD
+            .decode(&body)
+            .map_err(|err| format!("invalid base64 in {label} block: {err}"))?;
+        blocks += 1;
+    }
+    if blocks == 0 {
+        return Err("no PEM blocks found".to_string());
+    }
+    Ok(())
+}
+
+pub(crate) fn is_file_tag(tag: &str) -> bool {
+    matches!(tag, "file" | "base64file" | "pemfile" | "jsonfile")
+}
+
+// The value for e.g. `!pemfile certs/ca.pem`
+pub(crate) fn embed(tag: &str, path: &str) -> Value {
+    embed_from(Path::new(FILES_DIRECTORY), tag, path)
+}
+
+fn embed_from(files_directory: &Path, tag: &str, path: &str) -> Value {
+    match tag {
+        "file" => Value::String(read_text(files_directory, path, "!file")),
+        "base64file" => Value::String(base64::engine::general_purpose::STANDARD.encode(read(files_directory, path))),
+        "pemfile" => {
+            let text = read_text(files_directory, path, "!pemfile");
+            validate_pem(&text).unwrap_or_else(|err| panic!("Invalid PEM file \"{path}\": {err}"));
+            Value::String(text)
+        }
+        "jsonfile" => {
+            let text = read_text(files_directory, path, "!jsonfile");
+            serde_json::from_str(&text).unwrap_or_else(|err| panic!("Invalid JSON file \"{path}\": {err}"))
+        }
+        _ => panic!("Not a file tag: !{tag}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // a files directory containing the given files
+    fn files(contents: &[(&str, &[u8])]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in contents {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn file() {
+        let dir = files(&[("snippets/motd.txt", b"hello\n")]);
+        assert_eq!(embed_from(dir.path(), "file", "snippets/motd.txt"), Value::String("hello\n".to_string()));
+        assert_eq!(embed_from(dir.path(), "file", "./snippets/motd.txt"), Value::String("hello\n".to_string()));
+    }
+
+    #[test]
+    fn base64file() {
+        let dir = files(&[("key.bin", &[0, 255, 1])]);
+        assert_eq!(embed_from(dir.path(), "base64file", "key.bin"), Value::String("AP8B".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "!file file \"key.bin\" isn't UTF-8 text (try !base64file)")]
+    fn binary_file() {
+        let dir = files(&[("key.bin", &[0, 255, 1])]);
+        embed_from(dir.path(), "file", "key.bin");
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to read embedded file")]
+    fn missing_file() {
+        let dir = files(&[]);
+        embed_from(dir.path(), "file", "nope.txt");
+    }
+
+    #[test]
+    #[should_panic(expected = "Embedded file path \"../x\" must be relative, and stay within configuration/files/")]
+    fn parent_directory() {
+        let dir = files(&[]);
+        embed_from(&dir.path().join("sub"), "file", "../x");
+    }
+
+    #[test]
+    #[should_panic(expected = "Embedded file path \"/etc/hostname\" must be relative")]
+    fn absolute_path() {
+        let dir = files(&[]);
+        embed_from(dir.path(), "file", "/etc/hostname");
+    }
+
+    #[test]
+    #[should_panic(expected = "Embedded file \"link.txt\" resolves to")]
+    fn symlink_outside() {
+        let outside = files(&[("secret.txt", b"secret")]);
+        let dir = files(&[]);
+        std::os::unix::fs::symlink(outside.path().join("secret.txt"), dir.path().join("link.txt")).unwrap();
+        embed_from(dir.path(), "file", "link.txt");
+    }
+
+    #[test]
+    fn symlink_inside() {
+        let dir = files(&[("real.txt", b"x")]);
+        std::os::unix::fs::symlink(dir.path().join("real.txt"), dir.path().join("link.txt")).unwrap();
+        assert_eq!(embed_from(dir.path(), "file", "link.txt"), Value::String("x".to_string()));
+    }
+
+    const PEM: &str = "-----BEGIN CERTIFICATE-----\nAP8B\n-----END CERTIFICATE-----\n";
+
+    #[test]
+    fn pemfile() {
+        let dir = files(&[("ca.pem", PEM.as_bytes())]);
+        assert_eq!(embed_from(dir.path(), "pemfile", "ca.pem"), Value::String(PEM.to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid PEM file \"ca.pem\": expected a BEGIN line, got \"not a certificate\"")]
+    fn invalid_pemfile() {
+        let dir = files(&[("ca.pem", b"not a certificate\n")]);
+        embed_from(dir.path(), "pemfile", "ca.pem");
+    }
+
+    #[test]
+    fn pem_validation() {
+        assert_eq!(validate_pem(PEM), Ok(()));
+        assert_eq!(validate_pem(&format!("{PEM}\n{PEM}")), Ok(()));
+        assert_eq!(validate_pem(""), Err("no PEM blocks found".to_string()));
+        assert_eq!(
+            validate_pem("-----BEGIN X-----\nAP8B\n"),
+            Err("missing \"-----END X-----\"".to_string())
+        );
+        assert!(validate_pem("-----BEGIN X-----\nA!8B\n-----END X-----\n")
+            .unwrap_err()
+            .starts_with("invalid base64 in X block"));
+    }
+
+    #[test]
+    fn jsonfile() {
+        let dir = files(&[("hosts.json", br#"{"hosts": ["a", 1]}"#)]);
+        assert_eq!(
+            embed_from(dir.path(), "jsonfile", "hosts.json"),
+            serde_yaml::from_str::<Value>("{hosts: [a, 1]}").unwrap()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid JSON file \"hosts.json\": trailing comma")]
+    fn invalid_jsonfile() {
+        let dir = files(&[("hosts.json", br#"{"hosts": ["a",]}"#)]);
+        embed_from(dir.path(), "jsonfile", "hosts.json");
+    }
+}
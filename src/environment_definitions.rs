@@ -1,9 +1,24 @@
+use crate::postprocessors::PostprocessorCall;
+use glob::Pattern;
 use serde::Deserialize;
 use std::collections::HashMap;
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct EnvironmentDefinitions {
     pub environments: HashMap<String, EnvDef>,
+    // per-template configuration, by template filename
+    #[serde(default)]
+    pub templates: HashMap<String, TemplateDef>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub(crate) struct TemplateDef {
+    #[serde(default)]
+    pub postprocessors: Vec<PostprocessorCall>,
+    // a JSON Schema in configuration/schemas/ which the compiled output must match
+    pub schema: Option<String>,
+    // default escaping for a text template's substitutions: an escaping mode, or `auto` to go by the extension
+    pub escape: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -18,4 +33,92 @@ pub(crate) struct ConfDef {
     pub external_namespaces: Vec<String>,
     #[serde(default)]
     pub excluded_files: Vec<String>,
+    pub included_files: Option<Vec<String>>,
+    #[serde(default)]
+    pub overlays: Vec<String>,
+}
+
+fn file_pattern(pattern: &str) -> Pattern {
+    Pattern::new(pattern).unwrap_or_else(|err| panic!("Invalid file pattern \"{pattern}\": {err}"))
+}
+
+fn any_match(patterns: &[String], filename: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| file_pattern(pattern).matches(filename))
+}
+
+impl ConfDef {
+    // (so that an invalid pattern is reported up front, rather than when a template is first matched against it)
+    pub fn check_file_patterns(&self) -> Result<(), String> {
+        for pattern in self.excluded_files.iter().chain(self.included_files.iter().flatten()) {
+            Pattern::new(pattern).map_err(|err| format!("Invalid file pattern \"{pattern}\" ({err})"))?;
+        }
+        Ok(())
+    }
+
+    pub fn selects_file(&self, filename: &str) -> bool {
+        if let Some(included) = &self.included_files {
+            if !any_match(included, filename) {
+                return false;
+            }
+        }
+        !any_match(&self.excluded_files, filename)
+    }
+
+    pub fn unmatched_file_patterns(&self, filenames: &[String]) -> Vec<&str> {
+        self.excluded_files
+            .iter()
+            .chain(self.included_files.iter().flatten())
+            .filter(|pattern| {
+                let pattern = file_pattern(pattern);
+                !filenames.iter().any(|filename| pattern.matches(filename))
+            })
+            .map(|pattern| pattern.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(yaml: &str) -> ConfDef {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn selects_everything_by_default() {
+        assert!(conf("variables: []").selects_file("app.yml"));
+    }
+
+    #[test]
+    fn excludes_matching_files() {
+        let conf = conf("variables: []\nexcluded_files: [\"*.php\", debug.txt]");
+        assert!(!conf.selects_file("index.php"));
+        assert!(!conf.selects_file("debug.txt"));
+        assert!(conf.selects_file("app.yml"));
+    }
+
+    #[test]
+    fn only_includes_matching_files() {
+        let conf = conf("variables: []\nincluded_files: [\"app.*\"]\nexcluded_files: [app.php]");
+        assert!(conf.selects_file("app.yml"));
+        assert!(!conf.selects_file("app.php"));
+        assert!(!conf.selects_file("other.yml"));
+    }
+
+    #[test]
+    fn reports_patterns_matching_nothing() {
+        let conf = conf("variables: []\nincluded_files: [\"app.*\", \"*.conf\"]\nexcluded_files: [debgu.txt]");
+        let filenames = ["app.yml".to_string(), "debug.txt".to_string()];
+        assert_eq!(conf.unmatched_file_patterns(&filenames), ["debgu.txt", "*.conf"]);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(conf("variables: []\nexcluded_files: [\"*.php\"]").check_file_patterns().is_ok());
+        let err = conf("variables: []\nincluded_files: [\"app.*\", \"[ab\"]").check_file_patterns().unwrap_err();
+        assert!(err.starts_with("Invalid file pattern \"[ab\" ("), "{err}");
+    }
 }
@@ -0,0 +1,130 @@
+// Escaping applied when substituting values into text templates.
+// Each mode escapes for insertion *inside* an already-quoted string of that kind
+// (the template provides the quotes), so e.g. `PASSWORD='(( db/password | shell ))'`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum EscapeMode {
+    Raw,
+    // single-quoted shell string
+    Shell,
+    // double-quoted dotenv string
+    Dotenv,
+    // single-quoted PHP string
+    Php,
+    // JSON string (without the surrounding quotes)
+    JsonString,
+    // XML text or attribute value
+    Xml,
+}
+
+impl EscapeMode {
+    pub(crate) fn from_name(name: &str) -> Option<EscapeMode> {
+        match name {
+            "raw" => Some(EscapeMode::Raw),
+            "shell" => Some(EscapeMode::Shell),
+            "dotenv" => Some(EscapeMode::Dotenv),
+            "php" => Some(EscapeMode::Php),
+            "json_string" => Some(EscapeMode::JsonString),
+            "xml" => Some(EscapeMode::Xml),
+            _ => None,
+        }
+    }
+
+    // the default for `escape: auto`
+    pub(crate) fn for_filename(filename: &str) -> EscapeMode {
+        if filename.ends_with(".env") {
+            EscapeMode::Dotenv
+        } else if filename.ends_with(".php") {
+            EscapeMode::Php
+        } else {
+            EscapeMode::Raw
+        }
+    }
+
+    pub(crate) fn escape(&self, input: &str) -> String {
+        match self {
+            EscapeMode::Raw => input.to_string(),
+            EscapeMode::Shell => input.replace('\'', r"'\''"),
+            EscapeMode::Dotenv => {
+                let mut output = String::with_capacity(input.len());
+                for c in input.chars() {
+                    match c {
+                        '\\' | '"' | '$' | '`' => {
+                            output.push('\\');
+                            output.push(c);
+                        }
+                        '\n' => output.push_str(r"\n"),
+                        _ => output.push(c),
+                    }
+                }
+                output
+            }
+            EscapeMode::Php => input.replace('\\', r"\\").replace('\'', r"\'"),
+            EscapeMode::JsonString => {
+                let quoted = serde_json::to_string(input).unwrap();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            EscapeMode::Xml => {
+                let mut output = String::with_capacity(input.len());
+                for c in input.chars() {
+                    match c {
+                        '&' => output.push_str("&amp;"),
+                        '<' => output.push_str("&lt;"),
+                        '>' => output.push_str("&gt;"),
+                        '"' => output.push_str("&quot;"),
+                        '\'' => output.push_str("&apos;"),
+                        _ => output.push(c),
+                    }
+                }
+                output
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AWKWARD: &str = "it's \"$HOME\" \\ `id`\nnext";
+
+    #[test]
+    fn raw() {
+        assert_eq!(EscapeMode::Raw.escape(AWKWARD), AWKWARD);
+    }
+
+    #[test]
+    fn shell() {
+        assert_eq!(EscapeMode::Shell.escape(AWKWARD), "it'\\''s \"$HOME\" \\ `id`\nnext");
+    }
+
+    #[test]
+    fn dotenv() {
+        assert_eq!(EscapeMode::Dotenv.escape(AWKWARD), "it's \\\"\\$HOME\\\" \\\\ \\`id\\`\\nnext");
+    }
+
+    #[test]
+    fn php() {
+        assert_eq!(EscapeMode::Php.escape(AWKWARD), "it\\'s \"$HOME\" \\\\ `id`\nnext");
+    }
+
+    #[test]
+    fn json_string() {
+        assert_eq!(EscapeMode::JsonString.escape(AWKWARD), "it's \\\"$HOME\\\" \\\\ `id`\\nnext");
+    }
+
+    #[test]
+    fn xml() {
+        assert_eq!(
+            EscapeMode::Xml.escape("<a href=\"x\">it's & more</a>"),
+            "&lt;a href=&quot;x&quot;&gt;it&apos;s &amp; more&lt;/a&gt;"
+        );
+        assert_eq!(EscapeMode::Xml.escape(AWKWARD), "it&apos;s &quot;$HOME&quot; \\ `id`\nnext");
+    }
+
+    #[test]
+    fn extension_defaults() {
+        assert_eq!(EscapeMode::for_filename("app.env"), EscapeMode::Dotenv);
+        assert_eq!(EscapeMode::for_filename("settings.php"), EscapeMode::Php);
+        assert_eq!(EscapeMode::for_filename("nginx.conf"), EscapeMode::Raw);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::filters::type_name;
+use crate::references::Operator;
+use serde_yaml::{Number, Value};
+
+// Arithmetic is total: overflow, division by zero and mismatched types are errors rather than surprises.
+//
+// Integers stay integers (`/` truncates, like in Rust), anything involving a float is a float, and `+` also
+// concatenates two strings. Numbers and strings are never converted into each other implicitly.
+enum Numeric {
+    Integer(i64),
+    Float(f64),
+}
+
+fn numeric(n: &Number) -> Result<Numeric, String> {
+    if let Some(i) = n.as_i64() {
+        Ok(Numeric::Integer(i))
+    } else if n.is_f64() {
+        Ok(Numeric::Float(n.as_f64().unwrap()))
+    } else {
+        Err(format!("{n} is too large"))
+    }
+}
+
+fn verb(operator: Operator) -> &'static str {
+    match operator {
+        Operator::Add => "add",
+        Operator::Subtract => "subtract",
+        Operator::Multiply => "multiply",
+        Operator::Divide => "divide",
+        Operator::Remainder => "take the remainder of",
+    }
+}
+
+fn integer_operation(operator: Operator, a: i64, b: i64) -> Result<i64, String> {
+    if b == 0 && matches!(operator, Operator::Divide | Operator::Remainder) {
+        return Err(format!("can't {} {a} by zero", verb(operator)));
+    }
+    let result = match operator {
+        Operator::Add => a.checked_add(b),
+        Operator::Subtract => a.checked_sub(b),
+        Operator::Multiply => a.checked_mul(b),
+        Operator::Divide => a.checked_div(b),
+        Operator::Remainder => a.checked_rem(b),
+    };
+    result.ok_or_else(|| format!("{a} {} {b} overflows", operator.symbol()))
+}
+
+fn float_operation(operator: Operator, a: f64, b: f64) -> Result<f64, String> {
+    // (`{:?}` keeps e.g. 1e300 short)
+    if b == 0.0 && matches!(operator, Operator::Divide | Operator::Remainder) {
+        return Err(format!("can't {} {a:?} by zero", verb(operator)));
+    }
+    let result = match operator {
+        Operator::Add => a + b,
+        Operator::Subtract => a - b,
+        Operator::Multiply => a * b,
+        Operator::Divide => a / b,
+        Operator::Remainder => a % b,
+    };
+    if !result.is_finite() {
+        return Err(format!("{a:?} {} {b:?} overflows", operator.symbol()));
+    }
+    Ok(result)
+}
+
+pub(crate) fn apply_operator(operator: Operator, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match (lhs, rhs) {
+        (Value::String(a), Value::String(b)) if operator == Operator::Add => Ok(Value::String(a + &b)),
+        (Value::Number(a), Value::Number(b)) => match (numeric(&a)?, numeric(&b)?) {
+            (Numeric::Integer(a), Numeric::Integer(b)) => Ok(Value::Number(integer_operation(operator, a, b)?.into())),
+            (a, b) => {
+                let float = |n| match n {
+                    Numeric::Integer(i) => i as f64,
+                    Numeric::Float(f) => f,
+                };
+                Ok(Value::Number(float_operation(operator, float(a), float(b))?.into()))
+            }
+        },
+        (lhs @ (Value::String(_) | Value::Number(_)), rhs @ (Value::String(_) | Value::Number(_)))
+            if type_name(&lhs) != type_name(&rhs) =>
+        {
+            Err(format!(
+                "can't {} a {} and a {} (use \"${{...}}\" string interpolation to combine text and numbers)",
+                verb(operator),
+                type_name(&lhs),
+                type_name(&rhs)
+            ))
+        }
+        (lhs, rhs) => Err(format!(
+            "can't {} a {} and a {}",
+            verb(operator),
+            type_name(&lhs),
+            type_name(&rhs)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(operator: Operator, lhs: &str, rhs: &str) -> Result<Value, String> {
+        apply_operator(operator, serde_yaml::from_str(lhs).unwrap(), serde_yaml::from_str(rhs).unwrap())
+    }
+
+    fn value(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(apply(Operator::Add, "2", "3"), Ok(value("5")));
+        assert_eq!(apply(Operator::Subtract, "2", "3"), Ok(value("-1")));
+        assert_eq!(apply(Operator::Multiply, "4", "1024"), Ok(value("4096")));
+        assert_eq!(apply(Operator::Divide, "7", "2"), Ok(value("3")));
+        assert_eq!(apply(Operator::Remainder, "-7", "2"), Ok(value("-1")));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(apply(Operator::Multiply, "1.5", "2"), Ok(value("3.0")));
+        assert_eq!(apply(Operator::Divide, "7", "2.0"), Ok(value("3.5")));
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(
+            apply(Operator::Add, "9223372036854775807", "1"),
+            Err("9223372036854775807 + 1 overflows".to_string())
+        );
+        assert_eq!(
+            apply(Operator::Multiply, "-9223372036854775808", "2"),
+            Err("-9223372036854775808 * 2 overflows".to_string())
+        );
+        assert_eq!(
+            apply(Operator::Divide, "-9223372036854775808", "-1"),
+            Err("-9223372036854775808 / -1 overflows".to_string())
+        );
+        assert_eq!(
+            apply(Operator::Remainder, "-9223372036854775808", "-1"),
+            Err("-9223372036854775808 % -1 overflows".to_string())
+        );
+        assert_eq!(apply(Operator::Multiply, "1e300", "1e300"), Err("1e300 * 1e300 overflows".to_string()));
+        assert_eq!(
+            apply(Operator::Add, "18446744073709551615", "1"),
+            Err("18446744073709551615 is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(apply(Operator::Divide, "1", "0"), Err("can't divide 1 by zero".to_string()));
+        assert_eq!(apply(Operator::Remainder, "1", "0"), Err("can't take the remainder of 1 by zero".to_string()));
+        assert_eq!(apply(Operator::Divide, "1.5", "0.0"), Err("can't divide 1.5 by zero".to_string()));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(apply(Operator::Add, "abc", "def"), Ok(value("abcdef")));
+        assert_eq!(
+            apply(Operator::Subtract, "abc", "def"),
+            Err("can't subtract a string and a string".to_string())
+        );
+    }
+
+    #[test]
+    fn strings_and_numbers() {
+        let err = apply(Operator::Add, "host", "1").unwrap_err();
+        assert!(err.starts_with("can't add a string and a number (use \"${...}\""), "{err}");
+        let err = apply(Operator::Multiply, "2", "x").unwrap_err();
+        assert!(err.starts_with("can't multiply a number and a string (use"), "{err}");
+        assert_eq!(apply(Operator::Add, "1", "true"), Err("can't add a number and a boolean".to_string()));
+        assert_eq!(apply(Operator::Add, "[1]", "[2]"), Err("can't add a sequence and a sequence".to_string()));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::plugins::Plugins;
+use crate::processing::canonical_json;
+use base64::Engine;
+use lazy_static::lazy_static;
+use serde_yaml::Value;
+use std::collections::HashMap;
+
+// Filters are pure functions of their input value and (literal) arguments, applied with `(( name | filter(args) ))`
+type FilterFn = fn(Value, &[Value]) -> Result<Value, String>;
+
+lazy_static! {
+    static ref FILTERS: HashMap<&'static str, FilterFn> = {
+        let mut filters: HashMap<&'static str, FilterFn> = HashMap::new();
+        filters.insert("base64", base64_encode);
+        filters.insert("base64_decode", base64_decode);
+        filters.insert("upper", upper);
+        filters.insert("lower", lower);
+        filters.insert("trim", trim);
+        filters.insert("join", join);
+        filters.insert("split", split);
+        filters.insert("length", length);
+        filters.insert("json", json);
+        filters
+    };
+}
+
+pub(crate) fn filter_exists(name: &str, plugins: &Plugins) -> bool {
+    FILTERS.contains_key(name) || plugins.has_filter(name)
+}
+
+// Built-in filters take precedence over plugins with the same name
+pub(crate) fn apply_filter(name: &str, input: Value, args: &[Value], plugins: &Plugins) -> Result<Value, String> {
+    match FILTERS.get(name) {
+        Some(filter) => filter(input, args),
+        None if plugins.has_filter(name) => plugins.apply_filter(name, input, args),
+        None => Err(format!("Unknown filter \"{name}\"")),
+    }
+}
+
+pub(crate) fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "sequence",
+        Value::Mapping(_) => "mapping",
+        Value::Tagged(_) => "tagged value",
+    }
+}
+
+fn string_input(input: Value) -> Result<String, String> {
+    match input {
+        Value::String(s) => Ok(s),
+        other => Err(format!("expected a string, got a {}", type_name(&other))),
+    }
+}
+
+fn no_args(args: &[Value]) -> Result<(), String> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("expected no arguments, got {}", args.len()))
+    }
+}
+
+fn string_arg(args: &[Value]) -> Result<&str, String> {
+    match args {
+        [Value::String(s)] => Ok(s),
+        [other] => Err(format!("expected a string argument, got a {}", type_name(other))),
+        _ => Err(format!("expected 1 argument, got {}", args.len())),
+    }
+}
+
+fn base64_encode(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    let s = string_input(input)?;
+    Ok(Value::String(base64::engine::general_purpose::STANDARD.encode(s)))
+}
+
+fn base64_decode(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    let s = string_input(input)?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(s)
+        .map_err(|err| format!("invalid base64: {err}"))?;
+    String::from_utf8(bytes)
+        .map(Value::String)
+        .map_err(|_| "decoded base64 is not valid UTF-8".to_string())
+}
+
+fn upper(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    Ok(Value::String(string_input(input)?.to_uppercase()))
+}
+
+fn lower(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    Ok(Value::String(string_input(input)?.to_lowercase()))
+}
+
+fn trim(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    Ok(Value::String(string_input(input)?.trim().to_string()))
+}
+
+fn join(input: Value, args: &[Value]) -> Result<Value, String> {
+    let separator = string_arg(args)?;
+    let Value::Sequence(elems) = input else {
+        return Err(format!("expected a sequence, got a {}", type_name(&input)));
+    };
+    let strings = elems
+        .into_iter()
+        .map(|elem| match elem {
+            Value::String(s) => Ok(s),
+            Value::Number(n) => Ok(n.to_string()),
+            Value::Bool(b) => Ok(b.to_string()),
+            other => Err(format!("can't join a {}", type_name(&other))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::String(strings.join(separator)))
+}
+
+fn split(input: Value, args: &[Value]) -> Result<Value, String> {
+    let separator = string_arg(args)?;
+    let s = string_input(input)?;
+    Ok(Value::Sequence(
+        s.split(separator)
+            .map(|part| Value::String(part.to_string()))
+            .collect(),
+    ))
+}
+
+fn length(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    let len = match input {
+        Value::String(s) => s.chars().count(),
+        Value::Sequence(seq) => seq.len(),
+        Value::Mapping(map) => map.len(),
+        other => return Err(format!("can't take the length of a {}", type_name(&other))),
+    };
+    Ok(Value::Number(len.into()))
+}
+
+fn json(input: Value, args: &[Value]) -> Result<Value, String> {
+    no_args(args)?;
+    Ok(Value::String(canonical_json(&input)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(yaml: &str) -> Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn filter(name: &str, input: &str, args: &[&str]) -> Result<Value, String> {
+        let args = args.iter().map(|arg| value(arg)).collect::<Vec<_>>();
+        apply_filter(name, value(input), &args, &Plugins::default())
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(filter("base64", "pa's$word", &[]), Ok(value("cGEncyR3b3Jk")));
+        assert_eq!(filter("base64_decode", "cGEncyR3b3Jk", &[]), Ok(value("pa's$word")));
+        assert_eq!(
+            filter("base64_decode", "not base64!", &[]).unwrap_err(),
+            "invalid base64: Invalid symbol 32, offset 3."
+        );
+        assert_eq!(
+            filter("base64_decode", "/w==", &[]),
+            Err("decoded base64 is not valid UTF-8".to_string())
+        );
+        assert_eq!(filter("base64", "1", &[]), Err("expected a string, got a number".to_string()));
+    }
+
+    #[test]
+    fn case_and_whitespace() {
+        assert_eq!(filter("upper", "Straße", &[]), Ok(value("STRASSE")));
+        assert_eq!(filter("lower", "MiXeD", &[]), Ok(value("mixed")));
+        assert_eq!(filter("trim", "'  x y \n'", &[]), Ok(value("x y")));
+    }
+
+    #[test]
+    fn join_and_split() {
+        assert_eq!(filter("join", "[a, 1, true]", &["','"]), Ok(value("a,1,true")));
+        assert_eq!(filter("join", "[a, [b]]", &["','"]), Err("can't join a sequence".to_string()));
+        assert_eq!(filter("join", "a", &["','"]), Err("expected a sequence, got a string".to_string()));
+        assert_eq!(filter("join", "[a]", &[]), Err("expected 1 argument, got 0".to_string()));
+        assert_eq!(filter("join", "[a]", &["1"]), Err("expected a string argument, got a number".to_string()));
+        assert_eq!(filter("split", "a,b,,c", &["','"]), Ok(value("[a, b, '', c]")));
+    }
+
+    #[test]
+    fn length() {
+        assert_eq!(filter("length", "héllo", &[]), Ok(value("5")));
+        assert_eq!(filter("length", "[1, 2]", &[]), Ok(value("2")));
+        assert_eq!(filter("length", "{a: 1}", &[]), Ok(value("1")));
+        assert_eq!(filter("length", "1", &[]), Err("can't take the length of a number".to_string()));
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(filter("json", "{b: [1, x], a: null}", &[]), Ok(value(r#"'{"a":null,"b":[1,"x"]}'"#)));
+        assert_eq!(filter("json", "x", &[]), Ok(value(r#"'"x"'"#)));
+    }
+
+    #[test]
+    fn checks_arguments() {
+        assert_eq!(filter("upper", "x", &["1"]), Err("expected no arguments, got 1".to_string()));
+    }
+
+    #[test]
+    fn unknown_filter() {
+        assert_eq!(filter("nope", "x", &[]), Err("Unknown filter \"nope\"".to_string()));
+    }
+}
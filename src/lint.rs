@@ -0,0 +1,370 @@
+use crate::processing::{
+    directive_element, directive_key, find_definition, full_match_placeholder, with_error_catcher, Environment,
+    Template, TemplateFormat,
+};
+use crate::references::{parse_placeholder, Directive, Expr, Placeholder, Reference, StringPart};
+use crate::text_templates::{parse_text, Node};
+use crate::variable_definitions::MutationAction;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read_to_string, File};
+
+// Finds the variables which a template (or variable value, etc.) needs, without evaluating anything.
+//
+// Loop variables aren't needed, and a chain of `||` alternatives only needs one of them to be defined. What's
+// needed can depend on conditions, so this errs on the side of reporting everything that might be needed.
+//
+// Needs are recorded as groups of names, any one of which will do, e.g. `[a, b]` for `a || b`.
+struct Scanner<'a> {
+    location: String,
+    // loop variables in scope
+    locals: Vec<String>,
+    needed: &'a mut BTreeMap<Vec<String>, BTreeSet<String>>,
+    filters: BTreeSet<String>,
+}
+
+// What an expression needs, as groups of names where one of each group has to be defined
+fn requirements(expr: &Expr) -> Vec<Vec<String>> {
+    match expr {
+        Expr::Variable(name) => vec![vec![name.clone()]],
+        Expr::Literal(_) => vec![],
+        Expr::Interpolation(parts) => parts
+            .iter()
+            .filter_map(|part| match part {
+                StringPart::Variable(name) => Some(vec![name.clone()]),
+                StringPart::Text(_) => None,
+            })
+            .collect(),
+        Expr::Binary(lhs, _, rhs) => {
+            let mut needed = requirements(lhs);
+            needed.extend(requirements(rhs));
+            needed
+        }
+        // an alternative is used if everything it needs is defined, so only one of them has to be satisfied:
+        // (a and b) or c = (a or c) and (b or c)
+        Expr::Alternatives(alternatives) => alternatives
+            .iter()
+            .map(requirements)
+            .reduce(|either, or| {
+                either
+                    .iter()
+                    .flat_map(|a| {
+                        or.iter().map(move |b| {
+                            let mut group = a.clone();
+                            group.extend(b.iter().filter(|name| !a.contains(name)).cloned());
+                            group
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap(),
+    }
+}
+
+impl Scanner<'_> {
+    fn is_local(&self, name: &str) -> bool {
+        self.locals.iter().any(|local| {
+            name == local || name.strip_prefix(local.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        for group in requirements(expr) {
+            if !group.iter().any(|name| self.is_local(name)) {
+                self.needed.entry(group).or_default().insert(self.location.clone());
+            }
+        }
+    }
+
+    fn reference(&mut self, reference: &Reference) {
+        self.expr(&reference.expr);
+        self.filters
+            .extend(reference.filters.iter().map(|filter| filter.name.clone()));
+    }
+
+    fn with_locals(&mut self, names: &[String], scan: impl FnOnce(&mut Self)) {
+        let count = self.locals.len();
+        self.locals.extend(names.iter().cloned());
+        scan(self);
+        self.locals.truncate(count);
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::Text(_) => {}
+                // (which might turn out to be bash arithmetic)
+                Node::Substitution(reference, _) if reference.could_be_arithmetic() => {}
+                Node::Substitution(reference, _) => self.reference(reference),
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                    ..
+                } => {
+                    self.reference(condition);
+                    self.nodes(then);
+                    self.nodes(otherwise);
+                }
+                Node::For { names, iterable, body } => {
+                    self.reference(iterable);
+                    self.with_locals(names, |scanner| scanner.nodes(body));
+                }
+            }
+        }
+    }
+
+    fn string(&mut self, string: &str) {
+        match full_match_placeholder(string) {
+            Some(Placeholder::Reference(reference)) if !reference.could_be_arithmetic() => self.reference(&reference),
+            _ => self.nodes(&parse_text(string)),
+        }
+    }
+
+    fn directive(&mut self, directive: Directive, body: &Value) {
+        match directive {
+            Directive::If { condition, .. } => {
+                self.reference(&condition);
+                self.yaml(body);
+            }
+            Directive::For { names, iterable } => {
+                self.reference(&iterable);
+                self.with_locals(&names, |scanner| scanner.yaml(body));
+            }
+            Directive::Else | Directive::End => unreachable!(),
+        }
+    }
+
+    fn yaml(&mut self, value: &Value) {
+        match value {
+            Value::Null | Value::Bool(_) | Value::Number(_) => {}
+            Value::String(s) => self.string(s),
+            Value::Sequence(seq) => {
+                for elem in seq {
+                    match directive_element(elem) {
+                        Some((directive, body)) => self.directive(directive, &body),
+                        None => self.yaml(elem),
+                    }
+                }
+            }
+            Value::Mapping(map) => {
+                for (k, v) in map {
+                    match directive_key(k) {
+                        Some(directive) => self.directive(directive, v),
+                        None => {
+                            self.yaml(k);
+                            self.yaml(v);
+                        }
+                    }
+                }
+            }
+            Value::Tagged(tagged) => {
+                if tagged.tag == "var" {
+                    if let Some(Placeholder::Reference(reference)) =
+                        tagged.value.as_str().and_then(parse_placeholder)
+                    {
+                        self.reference(&reference);
+                    }
+                } else if tagged.tag == "delete" || tagged.tag == "replace" {
+                    // (in overlays)
+                    self.yaml(&tagged.value);
+                }
+                // anything else is literal, or an embedded file
+            }
+        }
+    }
+}
+
+fn scan(location: String, needed: &mut BTreeMap<Vec<String>, BTreeSet<String>>, scan: impl FnOnce(&mut Scanner)) {
+    let mut scanner = Scanner {
+        location,
+        locals: vec![],
+        needed,
+        filters: BTreeSet::new(),
+    };
+    scan(&mut scanner);
+}
+
+// The filters a template uses, so they can be checked before anything's compiled
+pub(crate) fn template_filters(template: &Template) -> BTreeSet<String> {
+    let mut scanner = Scanner {
+        location: String::new(),
+        locals: vec![],
+        needed: &mut BTreeMap::new(),
+        filters: BTreeSet::new(),
+    };
+    let content = read_to_string(&template.source_path).unwrap();
+    match template.format {
+        TemplateFormat::Text => scanner.nodes(&parse_text(&content)),
+        TemplateFormat::Yaml => scanner.yaml(&serde_yaml::from_str(&content).unwrap()),
+    }
+    scanner.filters
+}
+
+fn is_defined(name: &str, environment: &Environment) -> bool {
+    name == "environment/name"
+        || find_definition(&environment.definitions.definitions, name).is_some()
+        || environment
+            .expected_runtime_lookup_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+// The variables needed by a template, its overlays and mutations, and where they're needed
+fn template_references(template: &Template, environment: &Environment) -> BTreeMap<Vec<String>, BTreeSet<String>> {
+    let mut needed = BTreeMap::new();
+    let filename = template.source_path.file_name().unwrap().to_string_lossy().to_string();
+    let content = read_to_string(&template.source_path).unwrap();
+    match template.format {
+        TemplateFormat::Text => scan(filename, &mut needed, |scanner| scanner.nodes(&parse_text(&content))),
+        TemplateFormat::Yaml => {
+            let content: Value = serde_yaml::from_str(&content).unwrap();
+            scan(filename.clone(), &mut needed, |scanner| scanner.yaml(&content));
+
+            for overlay_dir in &environment.overlay_directories {
+                let overlay_path = overlay_dir.join(&filename);
+                if overlay_path.exists() {
+                    let overlay: Value = serde_yaml::from_reader(File::open(&overlay_path).unwrap()).unwrap();
+                    scan(overlay_path.to_string_lossy().to_string(), &mut needed, |scanner| {
+                        scanner.yaml(&overlay)
+                    });
+                }
+            }
+            for mutation in &environment.definitions.mutations {
+                if mutation.filename_pattern != filename {
+                    continue;
+                }
+                if let MutationAction::Add(_, value) | MutationAction::Replace(_, value) = &mutation.action {
+                    scan(format!("mutation of {filename}"), &mut needed, |scanner| scanner.yaml(value));
+                }
+            }
+        }
+    }
+    needed
+}
+
+// The variables needed (by the given templates, and by other variables) which aren't defined in an environment,
+// and where they're needed. A chain of alternatives is only reported (as `a || b`) if none of them are defined.
+pub(crate) fn undefined_references(
+    environment: &Environment,
+    templates: &[&Template],
+) -> BTreeMap<String, BTreeSet<String>> {
+    let mut needed: BTreeMap<Vec<String>, BTreeSet<String>> = BTreeMap::new();
+    for template in templates {
+        let template_needed = with_error_catcher(template.source_path.to_string_lossy().to_string(), &|| {
+            template_references(template, environment)
+        });
+        for (name, locations) in template_needed {
+            needed.entry(name).or_default().extend(locations);
+        }
+    }
+    for (name, value) in &environment.definitions.definitions {
+        scan(format!("variable \"{name}\""), &mut needed, |scanner| scanner.yaml(value));
+    }
+
+    needed
+        .into_iter()
+        .filter(|(group, _)| !group.iter().any(|name| is_defined(name, environment)))
+        .map(|(group, locations)| (group.join(" || "), locations))
+        .collect()
+}
+
+// Prints a table of the variables missing from any environment, returning whether everything's defined
+pub(crate) fn report(missing_by_environment: &BTreeMap<String, BTreeMap<String, BTreeSet<String>>>) -> bool {
+    let mut missing: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for environment_missing in missing_by_environment.values() {
+        for (name, locations) in environment_missing {
+            missing
+                .entry(name)
+                .or_default()
+                .extend(locations.iter().map(String::as_str));
+        }
+    }
+    if missing.is_empty() {
+        println!(
+            "All references are defined in all {} environments.",
+            missing_by_environment.len()
+        );
+        return true;
+    }
+
+    let mut header = vec!["variable".to_string()];
+    header.extend(missing_by_environment.keys().cloned());
+    header.push("referenced in".to_string());
+    let mut rows = vec![header];
+    for (name, locations) in &missing {
+        let mut row = vec![name.to_string()];
+        row.extend(missing_by_environment.values().map(|environment_missing| {
+            if environment_missing.contains_key(*name) {
+                "MISSING".to_string()
+            } else {
+                "ok".to_string()
+            }
+        }));
+        row.push(locations.iter().copied().collect::<Vec<_>>().join(", "));
+        rows.push(row);
+    }
+
+    let widths = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap())
+        .collect::<Vec<_>>();
+    for row in &rows {
+        let cells = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn needs(content: &str) -> Vec<Vec<String>> {
+        match parse_placeholder(content) {
+            Some(Placeholder::Reference(reference)) => requirements(&reference.expr),
+            other => panic!("{content:?} parsed as {other:?}"),
+        }
+    }
+
+    #[test]
+    fn any_alternative_will_do() {
+        assert_eq!(needs("a || b"), [["a", "b"]]);
+        assert_eq!(needs("a || b || c"), [["a", "b", "c"]]);
+    }
+
+    #[test]
+    fn literal_alternatives_need_nothing() {
+        assert!(needs("a || 30").is_empty());
+        assert!(needs("a || \"x\" || b").is_empty());
+    }
+
+    #[test]
+    fn alternatives_with_several_names() {
+        assert_eq!(needs("a + b || c"), [vec!["a", "c"], vec!["b", "c"]]);
+        assert_eq!(needs("\"${a}:${b}\" || a"), [vec!["a"], vec!["b", "a"]]);
+        assert_eq!(needs("(a || b) * c"), [vec!["a", "b"], vec!["c"]]);
+    }
+
+    // what a YAML string needs
+    fn string_needs(string: &str) -> Vec<Vec<String>> {
+        let mut needed = BTreeMap::new();
+        let mut scanner = Scanner {
+            location: "test".to_string(),
+            locals: vec![],
+            needed: &mut needed,
+            filters: BTreeSet::new(),
+        };
+        scanner.string(string);
+        needed.into_keys().collect()
+    }
+
+    #[test]
+    fn skips_what_could_be_bash_arithmetic() {
+        assert!(string_needs("(( i + 1 ))").is_empty());
+        assert_eq!(string_needs("$(( i + 1 )) (( a )) (( b + 1 | json ))"), [["a"], ["b"]]);
+    }
+}
@@ -1,17 +1,30 @@
+mod embedded_files;
 mod environment_definitions;
+mod escaping;
+mod expressions;
+mod filters;
+mod lint;
+mod output_schemas;
+mod overlays;
+mod plugins;
+mod postprocessors;
 mod processing;
+mod references;
+mod text_templates;
 mod variable_definitions;
+mod variable_schema;
 
-use environment_definitions::EnvironmentDefinitions;
+use environment_definitions::{EnvironmentDefinitions, TemplateDef};
+use escaping::EscapeMode;
 use processing::{Template, TemplateFormat};
-use variable_definitions::VariableSource;
+use variable_definitions::{OverridePolicy, VariableSource};
 
 use crate::processing::Environment;
 use clap::{Parser, ValueEnum};
 use path_clean::PathClean;
 use serde_yaml::Value;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{OsString};
 use std::fs::{read_dir, File};
 use std::io::{read_to_string, Write};
@@ -41,6 +54,17 @@ struct Args {
     #[deprecated]
     #[arg(value_enum, long = "format", default_value_t = OutputFormat::CanonicalJson)]
     format: OutputFormat,
+
+    #[arg(value_enum, long = "overrides", default_value_t = OverridePolicy::Silent)]
+    override_policy: OverridePolicy,
+
+    // list variable definitions which no template used, in any environment
+    #[arg(long = "report-unused")]
+    report_unused: bool,
+
+    // instead of compiling, check every environment for references to undefined variables
+    #[arg(long = "lint", conflicts_with = "report_unused")]
+    lint: bool,
     // TODO control over verboseness
 }
 
@@ -53,12 +77,16 @@ fn fix_path(path: &Path) -> PathBuf {
     abs_path.clean()
 }
 
+#[allow(deprecated)]
 fn fix_paths(args: Args) -> Args {
     Args {
         input_directory: fix_path(&args.input_directory).to_path_buf(),
         output_directory: fix_path(&args.output_directory).to_path_buf(),
         environments_file_path: fix_path(&args.environments_file_path).to_path_buf(),
         format: args.format,
+        override_policy: args.override_policy,
+        report_unused: args.report_unused,
+        lint: args.lint,
     }
 }
 
@@ -79,22 +107,30 @@ struct VarDefParseCache {
     cache: HashMap<PathBuf, Rc<VariableSource>>,
 }
 impl VarDefParseCache {
-    fn load(&mut self, path: &Path) -> io::Result<&Rc<VariableSource>> {
+    fn load(&mut self, name: &str, path: &Path) -> io::Result<&Rc<VariableSource>> {
         match self.cache.entry(path.to_path_buf()) {
             Entry::Occupied(v) => Ok(v.into_mut()),
             Entry::Vacant(v) => {
                 //println!("        loading {:?}!...", path);
-                Ok(v.insert(Rc::new(variable_definitions::load(path)?)))
+                Ok(v.insert(Rc::new(variable_definitions::load(name, path)?)))
             }
         }
     }
+
+    fn load_with_imports(&mut self, name: &str, sources: &mut Vec<(String, Rc<VariableSource>)>) -> io::Result<()> {
+        let mut load = |name: &str| {
+            let path = format!("configuration/variables/{name}.yml");
+            self.load(name, Path::new(&path)).map(Rc::clone)
+        };
+        variable_definitions::load_with_imports(name, &mut load, &mut vec![], sources)
+    }
 }
 
 fn determine_format(filename: &OsString) -> TemplateFormat {
     let as_str = filename.to_string_lossy();
     if as_str.ends_with(".yml") {
         TemplateFormat::Yaml
-    } else if vec![".conf", ".env", ".txt", ".php"]
+    } else if [".conf", ".env", ".txt", ".php"]
         .iter()
         .any(|ext| as_str.ends_with(ext))
     {
@@ -106,7 +142,7 @@ fn determine_format(filename: &OsString) -> TemplateFormat {
     }
 }
 
-fn get_templates() -> Vec<Template> {
+fn get_templates(template_defs: &HashMap<String, TemplateDef>) -> Vec<Template> {
     let stuff =
         read_dir(PathBuf::from("configuration/templates")).expect("Failed to list templates");
     stuff
@@ -115,9 +151,30 @@ fn get_templates() -> Vec<Template> {
             let template_dir_entry = template_listing.expect("WTF");
             let filename = template_dir_entry.file_name();
             let format = determine_format(&filename);
+            let template_def = template_defs
+                .get(filename.to_string_lossy().as_ref())
+                .cloned()
+                .unwrap_or_default();
+            if matches!(format, TemplateFormat::Text) && !template_def.postprocessors.is_empty() {
+                panic!("Post-processors can only be used with YAML templates, not {filename:?}")
+            }
+            if matches!(format, TemplateFormat::Text) && template_def.schema.is_some() {
+                panic!("Schemas can only be used with YAML templates, not {filename:?}")
+            }
+            if matches!(format, TemplateFormat::Yaml) && template_def.escape.is_some() {
+                panic!("Escaping can only be configured for text templates, not {filename:?}")
+            }
+            let escape_mode = match template_def.escape.as_deref() {
+                None => EscapeMode::Raw,
+                Some("auto") => EscapeMode::for_filename(&filename.to_string_lossy()),
+                Some(name) => EscapeMode::from_name(name)
+                    .unwrap_or_else(|| panic!("Unknown escaping mode \"{name}\" for {filename:?}")),
+            };
             Template {
                 format,
+                escape_mode,
                 source_path: template_dir_entry.path(),
+                postprocessors: template_def.postprocessors,
             }
         })
         .collect()
@@ -176,39 +233,75 @@ fn main() -> io::Result<()> {
 
     let envs_file = File::open(&args.environments_file_path)?;
     let env_defs: EnvironmentDefinitions = serde_yaml::from_reader(envs_file).unwrap();
-    let envs = env_defs.environments;
+    let envs = &env_defs.environments;
+    for (name, def) in envs {
+        def.configuration.check_file_patterns().map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Environment \"{name}\": {err}"))
+        })?;
+    }
+    let plugins = Rc::new(plugins::load_plugins()?);
+    for (template_name, template_def) in &env_defs.templates {
+        if !Path::new("configuration/templates").join(template_name).exists() {
+            eprintln!("WARN: Configuration for template \"{template_name}\", which doesn't exist.");
+        }
+        for postprocessor in &template_def.postprocessors {
+            if !postprocessors::postprocessor_exists(&postprocessor.name, &plugins) {
+                panic!(
+                    "Unknown post-processor \"{}\" for template \"{template_name}\"",
+                    &postprocessor.name
+                )
+            }
+        }
+    }
+
+    for template in get_templates(&env_defs.templates) {
+        let template_filters = processing::with_error_catcher(template.source_path.to_string_lossy().to_string(), &|| {
+            lint::template_filters(&template)
+        });
+        for filter in template_filters {
+            if !filters::filter_exists(&filter, &plugins) {
+                panic!("Unknown filter \"{filter}\" in template {:?}", template.source_path.file_name().unwrap())
+            }
+        }
+    }
+
+    let schema = variable_schema::load_schema()?;
+    let output_validators = output_schemas::load_validators(&env_defs.templates)?;
+    let mut schema_mismatches = 0;
 
     let mut cache = VarDefParseCache {
         cache: Default::default(),
     };
+    let mut used: HashSet<String> = HashSet::new();
+    let mut undefined_references = BTreeMap::new();
 
     for (name, def) in envs {
         //println!("{}:\n  {:?}", &name, &def);
 
         let mut output_dir = args.output_directory.clone();
         output_dir.push(Path::new(&format!("{}/configs", &name)));
-        fs::create_dir_all(&output_dir)?;
+        if !args.lint {
+            fs::create_dir_all(&output_dir)?;
+        }
 
-        let mut var_sources: Vec<Rc<VariableSource>> = vec![];
-        for var_source_path in def.configuration.variables {
+        let mut var_sources: Vec<(String, Rc<VariableSource>)> = vec![];
+        for var_source_name in &def.configuration.variables {
             //println!("    {}", &var_source);
-            let path = format!("configuration/variables/{}.yml", &var_source_path);
-            let var_source = cache.load(Path::new(&path))?;
-            var_sources.push(Rc::clone(var_source));
-            //println!("      {:?}", shit);
+            cache.load_with_imports(var_source_name, &mut var_sources)?;
         }
 
-        let mut combined_source: VariableSource =
-            variable_definitions::combine(var_sources.iter().map(|x| x.deref()).collect());
+        let var_sources: Vec<&VariableSource> = var_sources.iter().map(|(_, x)| x.deref()).collect();
+        let combined_source: VariableSource = variable_definitions::combine(name, var_sources.clone()).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Environment \"{name}\": {err}"))
+        })?;
+        variable_definitions::check_overrides(name, &var_sources, args.override_policy)?;
         //eprintln!("{:?}", &combined_source.mutations.iter().map(|m| &m.filename_pattern).collect::<Vec<_>>());
 
         // for (k,v) in &combined_source.definitions {
         //     println!("{}: {:?}", &k, &v)
         // }
-        combined_source
-            .definitions
-            .insert("environment/name".to_string(), Value::String(name.clone()));
         let environment = Environment {
+            name: name.clone(),
             definitions: combined_source,
             expected_runtime_lookup_prefixes: def
                 .configuration
@@ -216,16 +309,44 @@ fn main() -> io::Result<()> {
                 .iter()
                 .map(|ns| ns.to_string() + "/")
                 .collect(),
+            overlay_directories: def
+                .configuration
+                .overlays
+                .iter()
+                .map(|overlay| PathBuf::from(format!("configuration/overlays/{overlay}")))
+                .collect(),
+            plugins: Rc::clone(&plugins),
+            locals: Default::default(),
+            used: Default::default(),
+            expanding: Default::default(),
         };
 
-        for template in get_templates() {
-            let filename = template.source_path.file_name().unwrap().to_str().unwrap();
-            if def
-                .configuration
-                .excluded_files
+        let templates = get_templates(&env_defs.templates);
+        let template_filenames: Vec<String> = templates
+            .iter()
+            .map(|t| t.source_path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        for pattern in def.configuration.unmatched_file_patterns(&template_filenames) {
+            eprintln!("WARN: File pattern \"{pattern}\" for environment \"{name}\" matches no template.");
+        }
+
+        if args.lint {
+            let selected = templates
                 .iter()
-                .any(|ex_fn| ex_fn == filename)
-            {
+                .filter(|t| def.configuration.selects_file(&t.source_path.file_name().unwrap().to_string_lossy()))
+                .collect::<Vec<_>>();
+            undefined_references.insert(name.clone(), lint::undefined_references(&environment, &selected));
+            continue;
+        }
+
+        // (not when linting, as expanding values could fail on the undefined references it's meant to report)
+        variable_schema::validate(&schema, &environment).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Environment \"{name}\": {err}"))
+        })?;
+
+        for template in templates {
+            let filename = template.source_path.file_name().unwrap().to_str().unwrap();
+            if !def.configuration.selects_file(filename) {
                 eprintln!("Skipping {}", &filename);
                 continue;
             }
@@ -237,6 +358,18 @@ fn main() -> io::Result<()> {
             match template.format {
                 TemplateFormat::Yaml => {
                     let result = processing::process_yaml(&template, &environment, output_path.to_string_lossy().to_string());
+                    if let Some(validator) = output_validators.get(filename) {
+                        let errors = output_schemas::schema_errors(validator, &result);
+                        if !errors.is_empty() {
+                            eprintln!("ERROR: {filename} for environment \"{name}\" doesn't match its schema:");
+                            for error in errors {
+                                eprintln!("  {error}");
+                            }
+                            schema_mismatches += 1;
+                            continue;
+                        }
+                    }
+                    #[allow(deprecated)]
                     let output_fn = match args.format {
                         OutputFormat::CanonicalJson => write_canonical_json,
                         OutputFormat::Yaml => write_full_yaml,
@@ -249,6 +382,41 @@ fn main() -> io::Result<()> {
                 }
             }
         }
+        used.extend(environment.used.take());
+    }
+
+    if schema_mismatches > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{schema_mismatches} compiled template(s) don't match their schemas"),
+        ));
+    }
+    if args.lint && !lint::report(&undefined_references) {
+        std::process::exit(1);
+    }
+    if args.report_unused {
+        report_unused(&cache, &used);
     }
     Ok(())
 }
+
+// Only meaningful after compiling every environment, as a variable may only be used by one of them
+fn report_unused(cache: &VarDefParseCache, used: &HashSet<String>) {
+    let mut sources = cache.cache.values().collect::<Vec<_>>();
+    sources.sort_by(|a, b| a.name.cmp(&b.name));
+    for source in sources {
+        let mut unused = source
+            .definitions
+            .keys()
+            .filter(|name| !used.contains(*name))
+            .collect::<Vec<_>>();
+        if unused.is_empty() {
+            continue;
+        }
+        unused.sort();
+        eprintln!("Unused variables in {}:", &source.name);
+        for name in unused {
+            eprintln!("  {name}");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::environment_definitions::TemplateDef;
+use crate::variable_definitions::invalid;
+use jsonschema::Validator;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// JSON Schemas (draft 2020-12) for compiled YAML templates, configured per template:
+//
+// templates:
+//   application.yml:
+//     schema: application.schema.json
+//
+// Schemas live in this directory, and can be written in JSON or YAML.
+const SCHEMAS_DIRECTORY: &str = "configuration/schemas";
+
+fn load_schema(directory: &Path, schema_name: &str) -> io::Result<Validator> {
+    let path = directory.join(schema_name);
+    let file = File::open(&path).map_err(|err| io::Error::new(err.kind(), format!("{err}: {path:?}")))?;
+    let schema: Value =
+        serde_yaml::from_reader(file).map_err(|err| invalid(format!("Invalid schema {path:?}: {err}")))?;
+    let schema = serde_json::to_value(&schema)
+        .map_err(|err| invalid(format!("Schema {path:?} can't be represented as JSON: {err}")))?;
+    jsonschema::draft202012::new(&schema).map_err(|err| invalid(format!("Invalid schema {path:?}: {err}")))
+}
+
+// The validator for each template which has a schema, by template filename
+pub(crate) fn load_validators(template_defs: &HashMap<String, TemplateDef>) -> io::Result<HashMap<String, Validator>> {
+    let mut validators = HashMap::new();
+    for (template_name, template_def) in template_defs {
+        if let Some(schema_name) = &template_def.schema {
+            validators.insert(template_name.to_string(), load_schema(Path::new(SCHEMAS_DIRECTORY), schema_name)?);
+        }
+    }
+    Ok(validators)
+}
+
+// Everything wrong with a compiled template, each with the JSON pointer to where it's wrong
+pub(crate) fn schema_errors(validator: &Validator, content: &Value) -> Vec<String> {
+    let Ok(instance) = serde_json::to_value(content) else {
+        return vec!["output can't be represented as JSON".to_string()];
+    };
+    validator
+        .iter_errors(&instance)
+        .map(|err| {
+            let path = err.instance_path().to_string();
+            format!("at {}: {err}", if path.is_empty() { "/" } else { &path })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn validator(schema: &str) -> io::Result<Validator> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("app.schema.yml"), schema).unwrap();
+        load_schema(dir.path(), "app.schema.yml")
+    }
+
+    fn errors(schema: &str, content: &str) -> Vec<String> {
+        schema_errors(&validator(schema).unwrap(), &serde_yaml::from_str(content).unwrap())
+    }
+
+    const SCHEMA: &str = "
+        type: object
+        required: [server]
+        properties:
+          server:
+            properties:
+              port: {type: integer}
+              hosts: {type: array, items: {type: string}}
+    ";
+
+    #[test]
+    fn reports_where_output_is_wrong() {
+        assert!(errors(SCHEMA, "{server: {port: 80, hosts: [a]}}").is_empty());
+        assert_eq!(
+            errors(SCHEMA, "{server: {port: '80', hosts: [a, 1]}}"),
+            [r#"at /server/hosts/1: 1 is not of type "string""#, r#"at /server/port: "80" is not of type "integer""#]
+        );
+        assert_eq!(errors(SCHEMA, "[]"), [r#"at /: [] is not of type "object""#]);
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let err = validator("type: nope").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Invalid schema "), "{err}");
+        assert!(validator("type: [").err().unwrap().to_string().starts_with("Invalid schema "));
+    }
+
+    #[test]
+    fn missing_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = load_schema(dir.path(), "nope.json").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().ends_with("nope.json\""), "{err}");
+    }
+}
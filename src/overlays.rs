@@ -0,0 +1,118 @@
+use crate::variable_definitions::string_value;
+use serde_yaml::Value;
+
+use std::fs::File;
+use std::path::Path;
+
+// Overlay files are deep-merged onto the base template, with these rules:
+// * mappings are merged key by key (recursively)
+// * anything else (sequences, scalars, mismatched types) is replaced wholesale by the overlay's value
+// * `key: !delete` removes the key from the base (it must exist)
+// * `key: !replace {...}` replaces the base value instead of merging into it
+enum OverlayEntry {
+    Delete,
+    Replace(Value),
+    Merge(Value),
+}
+
+fn classify(value: Value) -> OverlayEntry {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == "delete" => {
+            if !tagged.value.is_null() {
+                panic!("!delete in overlay should not have a value (got {:?})", &tagged.value)
+            }
+            OverlayEntry::Delete
+        }
+        Value::Tagged(tagged) if tagged.tag == "replace" => OverlayEntry::Replace(tagged.value),
+        other => OverlayEntry::Merge(other),
+    }
+}
+
+fn merge(base: &mut Value, overlay: Value, path: &mut Vec<String>) {
+    match (base, overlay) {
+        (Value::Mapping(base_map), Value::Mapping(overlay_map)) => {
+            for (k, v) in overlay_map {
+                path.push(string_value(&k).unwrap_or_else(|| format!("{k:?}")));
+                match classify(v) {
+                    OverlayEntry::Delete => {
+                        base_map
+                            .remove(&k)
+                            .unwrap_or_else(|| panic!("Overlay can't delete missing {:?}", &path));
+                    }
+                    OverlayEntry::Replace(v) => {
+                        base_map.insert(k, v);
+                    }
+                    OverlayEntry::Merge(v) => match base_map.get_mut(&k) {
+                        Some(existing) => merge(existing, v, path),
+                        None => {
+                            base_map.insert(k, v);
+                        }
+                    },
+                }
+                path.pop();
+            }
+        }
+        (base, overlay) => match classify(overlay) {
+            OverlayEntry::Delete => panic!("Overlay can only !delete mapping entries (at {:?})", &path),
+            OverlayEntry::Replace(v) | OverlayEntry::Merge(v) => *base = v,
+        },
+    }
+}
+
+pub(crate) fn apply_overlay(content: &mut Value, overlay_path: &Path) {
+    let overlay: Value = serde_yaml::from_reader(File::open(overlay_path).unwrap())
+        .unwrap_or_else(|err| panic!("Failed to parse overlay {overlay_path:?}: {err}"));
+    merge(content, overlay, &mut vec![]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(base: &str, overlay: &str) -> Value {
+        let mut base: Value = serde_yaml::from_str(base).unwrap();
+        merge(&mut base, serde_yaml::from_str(overlay).unwrap(), &mut vec![]);
+        base
+    }
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merges_nested_mappings() {
+        assert_eq!(
+            merged("a: {b: 1, c: {d: 2, e: 3}}\nf: 4", "a: {c: {e: 5, g: 6}}"),
+            yaml("a: {b: 1, c: {d: 2, e: 5, g: 6}}\nf: 4")
+        );
+    }
+
+    #[test]
+    fn replaces_sequences() {
+        assert_eq!(merged("a: [1, 2, 3]", "a: [4]"), yaml("a: [4]"));
+    }
+
+    #[test]
+    fn replaces_mismatched_types() {
+        assert_eq!(merged("a: {b: 1}", "a: text"), yaml("a: text"));
+    }
+
+    #[test]
+    fn deletes() {
+        assert_eq!(merged("a: {b: 1, c: 2}", "a:\n  b: !delete"), yaml("a: {c: 2}"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Overlay can't delete missing [\"a\", \"x\"]")]
+    fn delete_of_missing_key_panics() {
+        merged("a: {b: 1}", "a:\n  x: !delete");
+    }
+
+    #[test]
+    fn replaces_instead_of_merging() {
+        assert_eq!(
+            merged("a: {b: 1, c: {d: 2}}", "a: !replace {c: {e: 3}}"),
+            yaml("a: {c: {e: 3}}")
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use crate::processing::canonical_json;
+use crate::references::is_modifier_name;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::path::Path;
+use std::{fs, io};
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+// Plugins are WebAssembly modules in `configuration/plugins/`, named by their filename (`jwt.wasm` is "jwt").
+//
+// They run in a sandbox: nothing is importable (so no filesystem, network, clock or randomness), each call gets a
+// fresh instance, and execution and memory are bounded, so a plugin is a pure and deterministic function.
+//
+// A plugin exports `memory` and `alloc(len: i32) -> i32`, plus `filter` and/or `postprocess`, both of which are
+// `(ptr: i32, len: i32) -> i64`, taking canonical JSON and returning `(ptr << 32) | len` of a JSON result:
+// * `filter` receives `{"input": ..., "args": [...]}`
+// * `postprocess` receives `{"input": ..., "options": ...}`
+// and either returns `{"ok": ...}` or `{"error": "message"}`.
+const PLUGINS_DIRECTORY: &str = "configuration/plugins";
+const FUEL: u64 = 100_000_000;
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+pub(crate) struct Plugin {
+    engine: Engine,
+    module: Module,
+}
+
+#[derive(Default)]
+pub(crate) struct Plugins {
+    plugins: HashMap<String, Plugin>,
+}
+
+impl std::fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.plugins.keys()).finish()
+    }
+}
+
+pub(crate) fn load_plugins() -> io::Result<Plugins> {
+    load_plugins_from(Path::new(PLUGINS_DIRECTORY))
+}
+
+fn load_plugins_from(directory: &Path) -> io::Result<Plugins> {
+    let mut plugins = Plugins::default();
+    if !directory.exists() {
+        return Ok(plugins);
+    }
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "wasm") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        // (otherwise it couldn't be used as `(( x | name ))`)
+        if !is_modifier_name(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Plugin {path:?} isn't usable, as names can only contain letters, digits and underscores"),
+            ));
+        }
+
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &fs::read(&path)?).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid plugin {path:?}: {err}"))
+        })?;
+        if let Some(import) = module.imports().next() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Plugin {path:?} imports \"{}::{}\", but plugins can't import anything",
+                    import.module(),
+                    import.name()
+                ),
+            ));
+        }
+        plugins.plugins.insert(name, Plugin { engine, module });
+    }
+    Ok(plugins)
+}
+
+impl Plugins {
+    pub(crate) fn has_filter(&self, name: &str) -> bool {
+        self.has_export(name, "filter")
+    }
+
+    pub(crate) fn has_postprocessor(&self, name: &str) -> bool {
+        self.has_export(name, "postprocess")
+    }
+
+    fn has_export(&self, name: &str, export: &str) -> bool {
+        self.plugins
+            .get(name)
+            .is_some_and(|plugin| plugin.module.get_export(export).is_some())
+    }
+
+    pub(crate) fn apply_filter(&self, name: &str, input: Value, args: &[Value]) -> Result<Value, String> {
+        let mut request = serde_yaml::Mapping::new();
+        request.insert("input".into(), input);
+        request.insert("args".into(), Value::Sequence(args.to_vec()));
+        self.call(name, "filter", Value::Mapping(request))
+    }
+
+    pub(crate) fn apply_postprocessor(&self, name: &str, content: &mut Value, options: &Value) -> Result<(), String> {
+        let mut request = serde_yaml::Mapping::new();
+        request.insert("input".into(), std::mem::take(content));
+        request.insert("options".into(), options.clone());
+        *content = self.call(name, "postprocess", Value::Mapping(request))?;
+        Ok(())
+    }
+
+    fn call(&self, name: &str, export: &str, request: Value) -> Result<Value, String> {
+        let plugin = self
+            .plugins
+            .get(name)
+            .ok_or_else(|| format!("No plugin \"{name}\""))?;
+        let response = plugin
+            .call(export, canonical_json(&request).as_bytes())
+            .map_err(|err| format!("plugin \"{name}\" failed: {err}"))?;
+        let response: Value = serde_json::from_slice(&response)
+            .map_err(|err| format!("plugin \"{name}\" returned invalid JSON: {err}"))?;
+        if let Some(ok) = response.get("ok") {
+            Ok(ok.clone())
+        } else if let Some(Value::String(err)) = response.get("error") {
+            Err(err.to_string())
+        } else {
+            Err(format!("plugin \"{name}\" returned neither \"ok\" nor \"error\""))
+        }
+    }
+}
+
+impl Plugin {
+    fn call(&self, export: &str, input: &[u8]) -> Result<Vec<u8>, wasmi::Error> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MEMORY_LIMIT)
+            .instances(1)
+            .build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL)?;
+
+        let linker = Linker::new(&self.engine);
+        let instance = linker.instantiate(&mut store, &self.module)?.start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| wasmi::Error::new("no exported memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+        let function = instance.get_typed_func::<(i32, i32), i64>(&store, export)?;
+
+        let len = i32::try_from(input.len()).map_err(|_| wasmi::Error::new("input too large"))?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let result = function.call(&mut store, (ptr, len))? as u64;
+
+        // (checked before copying anything, so a bad result can't make us allocate more than the plugin could)
+        let (ptr, len) = ((result >> 32) as usize, (result & 0xffff_ffff) as usize);
+        let data = memory.data(&store);
+        if len > MEMORY_LIMIT || ptr.checked_add(len).is_none_or(|end| end > data.len()) {
+            return Err(wasmi::Error::new(format!(
+                "output of {len} bytes at {ptr} is outside of the plugin's memory"
+            )));
+        }
+        Ok(data[ptr..ptr + len].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(files: &[(&str, &[u8])]) -> io::Result<Plugins> {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+        load_plugins_from(dir.path())
+    }
+
+    // a plugin module with the given exports, plus memory and a bump allocator
+    fn module(exports: &str) -> Vec<u8> {
+        wat::parse_str(format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (func (export "alloc") (param $len i32) (result i32)
+                    (global.get $next)
+                    (global.set $next (i32.add (global.get $next) (local.get $len))))
+                {exports})"#
+        ))
+        .unwrap()
+    }
+
+    // returns the request itself (which is always canonical JSON), as `{"ok": request}`
+    const ECHO: &str = r#"
+        (data (i32.const 0) "{\"ok\":")
+        (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+            (local $out i32)
+            (local.set $out (global.get $next))
+            (memory.copy (local.get $out) (i32.const 0) (i32.const 6))
+            (memory.copy (i32.add (local.get $out) (i32.const 6)) (local.get $ptr) (local.get $len))
+            (i32.store8 (i32.add (local.get $out) (i32.add (local.get $len) (i32.const 6))) (i32.const 125))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+                    (i64.extend_i32_u (i32.add (local.get $len) (i32.const 7)))))"#;
+
+    // a plugin whose `filter` and `postprocess` both return `(ptr << 32) | len` of the given JSON
+    fn returning(json: &str) -> Vec<u8> {
+        let escaped = json.replace('"', "\\\"");
+        module(&format!(
+            r#"(data (i32.const 0) "{escaped}")
+               (func (export "filter") (param i32 i32) (result i64) (i64.const {len}))
+               (func (export "postprocess") (param i32 i32) (result i64) (i64.const {len}))"#,
+            len = json.len()
+        ))
+    }
+
+    fn value(yaml: &str) -> Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn no_plugins() {
+        assert!(load_plugins_from(Path::new("no/such/directory")).unwrap().plugins.is_empty());
+        assert!(load(&[("notes.txt", b"not a plugin")]).unwrap().plugins.is_empty());
+    }
+
+    #[test]
+    fn rejects_unusable_names() {
+        for name in ["my-filter.wasm", "a.b.wasm"] {
+            let err = load(&[(name, b"")]).unwrap_err();
+            assert!(
+                err.to_string().ends_with("isn't usable, as names can only contain letters, digits and underscores"),
+                "{name}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_imports() {
+        let clock = wat::parse_str(r#"(module (import "env" "now" (func (result i64))))"#).unwrap();
+        let err = load(&[("clock.wasm", &clock)]).unwrap_err();
+        assert!(
+            err.to_string().ends_with("imports \"env::now\", but plugins can't import anything"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        let err = load(&[("broken.wasm", b"not wasm")]).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid plugin"), "{err}");
+    }
+
+    #[test]
+    fn filter() {
+        let plugins = load(&[("echo.wasm", &module(ECHO))]).unwrap();
+        assert!(plugins.has_filter("echo"));
+        assert!(!plugins.has_postprocessor("echo"));
+        assert_eq!(
+            plugins.apply_filter("echo", value("x"), &[value("1")]),
+            Ok(value("{args: [1], input: x}"))
+        );
+    }
+
+    #[test]
+    fn postprocessor() {
+        let plugins = load(&[("replace.wasm", &returning(r#"{"ok":{"replaced":true}}"#))]).unwrap();
+        let mut content = value("{a: 1}");
+        assert_eq!(plugins.apply_postprocessor("replace", &mut content, &Value::Null), Ok(()));
+        assert_eq!(content, value("{replaced: true}"));
+    }
+
+    #[test]
+    fn errors() {
+        let plugins = load(&[
+            ("fails.wasm", &returning(r#"{"error":"bad input"}"#)),
+            ("neither.wasm", &returning(r#"{"result":1}"#)),
+            ("garbage.wasm", &returning("{nope")),
+        ])
+        .unwrap();
+        assert_eq!(plugins.apply_filter("fails", Value::Null, &[]), Err("bad input".to_string()));
+        assert_eq!(
+            plugins.apply_filter("neither", Value::Null, &[]),
+            Err("plugin \"neither\" returned neither \"ok\" nor \"error\"".to_string())
+        );
+        assert!(plugins
+            .apply_filter("garbage", Value::Null, &[])
+            .unwrap_err()
+            .starts_with("plugin \"garbage\" returned invalid JSON"));
+    }
+
+    #[test]
+    fn runs_out_of_fuel() {
+        let plugins = load(&[(
+            "spin.wasm",
+            &module(r#"(func (export "filter") (param i32 i32) (result i64) (loop $l (br $l)) (i64.const 0))"#),
+        )])
+        .unwrap();
+        let err = plugins.apply_filter("spin", Value::Null, &[]).unwrap_err();
+        assert!(err.starts_with("plugin \"spin\" failed") && err.contains("fuel"), "{err}");
+    }
+
+    #[test]
+    fn memory_limit() {
+        // (1025 pages of 64KiB is just over the limit)
+        let plugins = load(&[(
+            "greedy.wasm",
+            &module(
+                r#"(data (i32.const 0) "{\"error\":\"no memory\"}")
+                   (func (export "filter") (param i32 i32) (result i64)
+                       (if (i32.eq (memory.grow (i32.const 1024)) (i32.const -1))
+                           (then (return (i64.const 21))))
+                       (unreachable))"#,
+            ),
+        )])
+        .unwrap();
+        assert_eq!(plugins.apply_filter("greedy", Value::Null, &[]), Err("no memory".to_string()));
+
+        let too_big = wat::parse_str(r#"(module (memory (export "memory") 1025))"#).unwrap();
+        let plugins = load(&[("big.wasm", &too_big)]).unwrap();
+        assert_eq!(
+            plugins.apply_filter("big", Value::Null, &[]),
+            Err("plugin \"big\" failed: out of bounds memory allocation".to_string())
+        );
+    }
+
+    #[test]
+    fn output_bounds() {
+        let out_of_range = |result: u64| {
+            module(&format!(r#"(func (export "filter") (param i32 i32) (result i64) (i64.const {result}))"#))
+        };
+        let plugins = load(&[
+            // (the memory is one page, 65536 bytes)
+            ("past_end.wasm", &out_of_range((65000 << 32) | 1000)),
+            ("far_away.wasm", &out_of_range(0xffff_ffff_0000_0010)),
+            ("too_long.wasm", &out_of_range(0xffff_ffff)),
+        ])
+        .unwrap();
+        assert_eq!(
+            plugins.apply_filter("past_end", Value::Null, &[]),
+            Err("plugin \"past_end\" failed: output of 1000 bytes at 65000 is outside of the plugin's memory".to_string())
+        );
+        assert_eq!(
+            plugins.apply_filter("far_away", Value::Null, &[]),
+            Err("plugin \"far_away\" failed: output of 16 bytes at 4294967295 is outside of the plugin's memory"
+                .to_string())
+        );
+        assert_eq!(
+            plugins.apply_filter("too_long", Value::Null, &[]),
+            Err("plugin \"too_long\" failed: output of 4294967295 bytes at 0 is outside of the plugin's memory"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn built_in_filters_win() {
+        let plugins = load(&[("upper.wasm", &module(ECHO))]).unwrap();
+        assert_eq!(
+            crate::filters::apply_filter("upper", value("x"), &[], &plugins),
+            Ok(value("X"))
+        );
+    }
+}
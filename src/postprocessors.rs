@@ -0,0 +1,304 @@
+use crate::plugins::Plugins;
+use crate::processing::TryNavigate;
+use crate::variable_definitions::string_value;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Post-processors are applied to a YAML template's expanded value, in the order configured for the template:
+//
+// templates:
+//   application.yml:
+//     postprocessors:
+//       - strip_nulls
+//       - join_list: {path: spring/profiles/active, separator: ", "}
+//
+// They're handy for working around frameworks with an annoying config format.
+type PostprocessorFn = fn(&mut Value, &Value) -> Result<(), String>;
+
+lazy_static! {
+    static ref POSTPROCESSORS: HashMap<&'static str, PostprocessorFn> = {
+        let mut postprocessors: HashMap<&'static str, PostprocessorFn> = HashMap::new();
+        postprocessors.insert("join_list", join_list);
+        postprocessors.insert("strip_nulls", strip_nulls);
+        postprocessors.insert("key_case", key_case);
+        postprocessors
+    };
+}
+
+// A post-processor's name, and its options (or null)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Value")]
+pub(crate) struct PostprocessorCall {
+    pub(crate) name: String,
+    pub(crate) options: Value,
+}
+
+impl TryFrom<Value> for PostprocessorCall {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let (name, options) = match value {
+            Value::String(name) => (name, Value::Null),
+            Value::Mapping(m) if m.len() == 1 => {
+                let (name, options) = m.into_iter().next().unwrap();
+                (
+                    string_value(&name).ok_or("Post-processor name must be a string")?,
+                    options,
+                )
+            }
+            other => {
+                return Err(format!(
+                    "Post-processor must be a name or a single-entry mapping of name to options, got {other:?}"
+                ))
+            }
+        };
+        Ok(PostprocessorCall { name, options })
+    }
+}
+
+// Built-in post-processors take precedence over plugins with the same name
+pub(crate) fn postprocessor_exists(name: &str, plugins: &Plugins) -> bool {
+    POSTPROCESSORS.contains_key(name) || plugins.has_postprocessor(name)
+}
+
+pub(crate) fn apply_postprocessor(call: &PostprocessorCall, content: &mut Value, plugins: &Plugins, template: &Path) {
+    let result = match POSTPROCESSORS.get(call.name.as_str()) {
+        Some(postprocessor) => postprocessor(content, &call.options),
+        None if plugins.has_postprocessor(&call.name) => {
+            plugins.apply_postprocessor(&call.name, content, &call.options)
+        }
+        None => Err("no such post-processor".to_string()),
+    };
+    result.unwrap_or_else(|err| panic!("Post-processor \"{}\" failed for template {template:?}: {err}", &call.name));
+}
+
+fn option<'a>(options: &'a Value, name: &str) -> Option<&'a Value> {
+    options.as_mapping().and_then(|m| m.get(name))
+}
+
+fn string_option(options: &Value, name: &str) -> Result<Option<String>, String> {
+    match option(options, name) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.to_string())),
+        Some(other) => Err(format!("option \"{name}\" must be a string, got {other:?}")),
+    }
+}
+
+// Replaces the list at `path` (slash-separated) with a string of its joined elements, e.g. for Spring profiles
+fn join_list(content: &mut Value, options: &Value) -> Result<(), String> {
+    let path = string_option(options, "path")?.ok_or("missing \"path\" option")?;
+    let separator = string_option(options, "separator")?.unwrap_or(", ".to_string());
+    let segments = path.split('/').map(str::to_string).collect::<Vec<_>>();
+
+    let Some(target) = content
+        .try_navigate(&segments)
+        .map_err(|err| format!("can't follow path \"{path}\": {err}"))?
+    else {
+        return Ok(());
+    };
+    if let Value::Sequence(elems) = target {
+        let joined = elems
+            .iter()
+            .map(|elem| match elem {
+                Value::String(s) => Ok(s.to_string()),
+                Value::Number(n) => Ok(n.to_string()),
+                other => Err(format!("can't join {other:?}")),
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .join(&separator);
+        *target = Value::String(joined);
+    }
+    Ok(())
+}
+
+// Removes mapping entries with null values, everywhere
+fn strip_nulls(content: &mut Value, _options: &Value) -> Result<(), String> {
+    match content {
+        Value::Mapping(m) => {
+            m.retain(|_k, v| !v.is_null());
+            for v in m.values_mut() {
+                strip_nulls(v, &Value::Null)?;
+            }
+        }
+        Value::Sequence(s) => {
+            for v in s.iter_mut() {
+                strip_nulls(v, &Value::Null)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn words(key: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut current = String::new();
+    let mut previous_lowercase = false;
+    for c in key.chars() {
+        if c == '_' || c == '-' || c == ' ' {
+            words.push(std::mem::take(&mut current));
+            previous_lowercase = false;
+            continue;
+        }
+        if c.is_uppercase() && previous_lowercase {
+            words.push(std::mem::take(&mut current));
+        }
+        previous_lowercase = c.is_lowercase() || c.is_ascii_digit();
+        current.extend(c.to_lowercase());
+    }
+    words.push(current);
+    words.into_iter().filter(|w| !w.is_empty()).collect()
+}
+
+fn convert_case(key: &str, case: &str) -> Result<String, String> {
+    let words = words(key);
+    match case {
+        "snake" => Ok(words.join("_")),
+        "kebab" => Ok(words.join("-")),
+        "camel" => Ok(words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| {
+                if i == 0 {
+                    return word.to_string();
+                }
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            })
+            .collect()),
+        _ => Err(format!("unknown case \"{case}\" (expected camel, snake or kebab)")),
+    }
+}
+
+// Converts all (string) mapping keys to camel, snake or kebab case, e.g. `key_case: camel`
+fn key_case(content: &mut Value, options: &Value) -> Result<(), String> {
+    let case = string_value(options).ok_or("expected the case to convert to, e.g. \"key_case: camel\"")?;
+    match content {
+        Value::Mapping(m) => {
+            let mut converted = Mapping::with_capacity(m.len());
+            for (k, mut v) in std::mem::take(m) {
+                key_case(&mut v, options)?;
+                let k = match k {
+                    Value::String(s) => Value::String(convert_case(&s, &case)?),
+                    other => other,
+                };
+                if converted.contains_key(&k) {
+                    return Err(format!("more than one key converts to {k:?}"));
+                }
+                converted.insert(k, v);
+            }
+            *m = converted;
+        }
+        Value::Sequence(s) => {
+            for v in s.iter_mut() {
+                key_case(v, options)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(yaml: &str) -> Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn postprocessed(postprocessor: PostprocessorFn, content: &str, options: &str) -> Result<Value, String> {
+        let mut content = value(content);
+        postprocessor(&mut content, &value(options)).map(|()| content)
+    }
+
+    #[test]
+    fn join_list() {
+        let content = "{spring: {profiles: {active: [a, 1]}}}";
+        assert_eq!(
+            postprocessed(super::join_list, content, "{path: spring/profiles/active}"),
+            Ok(value("{spring: {profiles: {active: 'a, 1'}}}"))
+        );
+        assert_eq!(
+            postprocessed(super::join_list, content, "{path: spring/profiles/active, separator: ','}"),
+            Ok(value("{spring: {profiles: {active: 'a,1'}}}"))
+        );
+        // a missing path, or something other than a list there, is left alone
+        assert_eq!(postprocessed(super::join_list, content, "{path: spring/x/y}"), Ok(value(content)));
+        assert_eq!(postprocessed(super::join_list, content, "{path: spring}"), Ok(value(content)));
+    }
+
+    #[test]
+    fn join_list_errors() {
+        assert_eq!(
+            postprocessed(super::join_list, "{spring: {profiles: dev}}", "{path: spring/profiles/active}"),
+            Err(r#"can't follow path "spring/profiles/active": expected a mapping before "active", got String("dev")"#
+                .to_string())
+        );
+        assert_eq!(
+            postprocessed(super::join_list, "{a: [[b]]}", "{path: a}"),
+            Err("can't join Sequence [String(\"b\")]".to_string())
+        );
+        assert_eq!(
+            postprocessed(super::join_list, "{a: [b]}", "{}"),
+            Err("missing \"path\" option".to_string())
+        );
+        assert_eq!(
+            postprocessed(super::join_list, "{a: [b]}", "{path: a, separator: 1}"),
+            Err("option \"separator\" must be a string, got Number(1)".to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Post-processor "join_list" failed for template "templates/app.yml": can't follow path "a/b""#
+    )]
+    fn failure_names_the_template() {
+        let call = PostprocessorCall::try_from(value("{join_list: {path: a/b}}")).unwrap();
+        apply_postprocessor(&call, &mut value("{a: 1}"), &Plugins::default(), Path::new("templates/app.yml"));
+    }
+
+    #[test]
+    fn strip_nulls() {
+        assert_eq!(
+            postprocessed(super::strip_nulls, "{a: null, b: {c: ~, d: 1}, e: [{f: null}, null]}", "~"),
+            Ok(value("{b: {d: 1}, e: [{}, null]}"))
+        );
+    }
+
+    #[test]
+    fn convert_case() {
+        for key in ["max_pool_size", "maxPoolSize", "max-pool-size", "MaxPoolSize", "MAX_POOL_SIZE"] {
+            assert_eq!(super::convert_case(key, "camel"), Ok("maxPoolSize".to_string()), "{key}");
+            assert_eq!(super::convert_case(key, "snake"), Ok("max_pool_size".to_string()), "{key}");
+            assert_eq!(super::convert_case(key, "kebab"), Ok("max-pool-size".to_string()), "{key}");
+        }
+        assert_eq!(super::convert_case("http2Enabled", "snake"), Ok("http2_enabled".to_string()));
+        assert_eq!(
+            super::convert_case("x", "pascal"),
+            Err("unknown case \"pascal\" (expected camel, snake or kebab)".to_string())
+        );
+    }
+
+    #[test]
+    fn key_case() {
+        assert_eq!(
+            postprocessed(super::key_case, "{pool_size: 1, servers: [{host_name: a}], 1: x}", "camel"),
+            Ok(value("{poolSize: 1, servers: [{hostName: a}], 1: x}"))
+        );
+        assert_eq!(
+            postprocessed(super::key_case, "{pool_size: 1, poolSize: 2}", "camel"),
+            Err("more than one key converts to String(\"poolSize\")".to_string())
+        );
+        assert_eq!(
+            postprocessed(super::key_case, "{a: 1}", "~"),
+            Err("expected the case to convert to, e.g. \"key_case: camel\"".to_string())
+        );
+    }
+}
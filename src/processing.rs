@@ -1,21 +1,42 @@
+use crate::embedded_files::{embed, is_file_tag};
+use crate::escaping::EscapeMode;
+use crate::expressions::apply_operator;
+use crate::filters::apply_filter;
+use crate::overlays::apply_overlay;
+use crate::plugins::Plugins;
+use crate::postprocessors::{apply_postprocessor, PostprocessorCall};
+use crate::references::{parse_placeholder, Directive, Expr, Placeholder, Reference, StringPart};
+use crate::text_templates::{parse_text, Node};
 use crate::variable_definitions::{string_value, MutationAction, VariableSource};
 use lazy_static::lazy_static;
-use regex::{Captures, Regex};
+use regex::Regex;
 
+use serde_yaml::value::TaggedValue;
 use serde_yaml::{Mapping, Sequence, Value};
 
 use std::fs::{read_to_string, File};
 
-use std::cell::Cell;
-use std::panic::PanicInfo;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::panic::PanicHookInfo;
 
 use std::path::PathBuf;
+use std::rc::Rc;
 
 // TODO support working in YAML but with Canonical JSON (RFC) output
 #[derive(Debug)]
 pub(crate) struct Environment {
+    pub(crate) name: String,
     pub(crate) definitions: VariableSource,
     pub(crate) expected_runtime_lookup_prefixes: Vec<String>,
+    pub(crate) overlay_directories: Vec<PathBuf>,
+    pub(crate) plugins: Rc<Plugins>,
+    // loop variables currently in scope (innermost last)
+    pub(crate) locals: RefCell<Vec<(String, Value)>>,
+    // the definitions actually looked up (e.g. `features/*` rather than `features/x`), to find unused ones
+    pub(crate) used: RefCell<HashSet<String>>,
+    // the variables currently being expanded, outermost first, to catch cycles
+    pub(crate) expanding: RefCell<Vec<String>>,
 }
 
 #[derive(Debug)]
@@ -27,7 +48,9 @@ pub(crate) enum TemplateFormat {
 #[derive(Debug)]
 pub(crate) struct Template {
     pub(crate) format: TemplateFormat,
+    pub(crate) escape_mode: EscapeMode,
     pub(crate) source_path: PathBuf,
+    pub(crate) postprocessors: Vec<PostprocessorCall>,
 }
 
 fn mapping_value(val: &mut Value) -> Option<&mut Mapping> {
@@ -50,11 +73,11 @@ impl Navigate for Mapping {
     fn navigate(&mut self, path: &[String]) -> &mut Value {
         let next = self
             .get_mut(
-                path.get(0)
+                path.first()
                     .unwrap_or_else(|| panic!("WTF, regarding path {:?}", &path)),
             )
             .unwrap_or_else(|| panic!("WTF, regarding missing value at {:?}", &path));
-        return next.navigate(&path[1..]);
+        next.navigate(&path[1..])
     }
 }
 impl Navigate for Value {
@@ -66,26 +89,31 @@ impl Navigate for Value {
     }
 }
 
-trait TryNavigate {
-    fn try_navigate(&mut self, path: &[String]) -> Option<&mut Value>;
+// None if something along the path is missing, or an error describing whatever is in the way which isn't a mapping
+pub(crate) trait TryNavigate {
+    fn try_navigate(&mut self, path: &[String]) -> Result<Option<&mut Value>, String>;
 }
 impl TryNavigate for Mapping {
-    fn try_navigate(&mut self, path: &[String]) -> Option<&mut Value> {
+    fn try_navigate(&mut self, path: &[String]) -> Result<Option<&mut Value>, String> {
         let next = self.get_mut(
-            path.get(0)
+            path.first()
                 .unwrap_or_else(|| panic!("WTF, regarding path {:?}", &path)),
         );
-        next.and_then(|next| next.try_navigate(&path[1..]))
+        match next {
+            None => Ok(None),
+            Some(next) => next.try_navigate(&path[1..]),
+        }
     }
 }
 impl TryNavigate for Value {
-    fn try_navigate(&mut self, path: &[String]) -> Option<&mut Value> {
+    fn try_navigate(&mut self, path: &[String]) -> Result<Option<&mut Value>, String> {
         if path.is_empty() {
-            return Some(self);
+            return Ok(Some(self));
+        }
+        match self {
+            Value::Mapping(m) => m.try_navigate(path),
+            other => Err(format!("expected a mapping before \"{}\", got {other:?}", path.join("/"))),
         }
-        mapping_value(self)
-            .expect("not a mapping")
-            .try_navigate(path)
     }
 }
 
@@ -127,23 +155,102 @@ fn apply_mutation(mutation: &MutationAction, content: &mut Value) {
     }
 }
 
-fn _lookup(reference_name: &str, environment: &Environment) -> Option<Value> {
-    let maybe = environment.definitions.definitions.get(reference_name);
-    match maybe {
+// The definition for a name, falling back to wildcards: `a/b/c` can be defined by `a/b/*` or `a/*`
+pub(crate) fn find_definition<'a, V>(
+    definitions: &'a HashMap<String, V>,
+    reference_name: &str,
+) -> Option<(&'a String, &'a V)> {
+    match definitions.get_key_value(reference_name) {
         None => {
-            let last_slash = reference_name[..reference_name.len() - 2].rfind('/');
+            let last_slash = reference_name.strip_suffix("/*").unwrap_or(reference_name).rfind('/');
             match last_slash {
                 None => None,
-                Some(split_pos) => _lookup(
+                Some(split_pos) => find_definition(
+                    definitions,
                     &(reference_name[..split_pos].to_string() + "/*"),
-                    environment,
                 ),
             }
         }
-        Some(value) => Some(value.clone()),
+        found => found,
     }
 }
-fn lookup(reference_name: &str, environment: &Environment) -> Option<Value> {
+
+fn _lookup(reference_name: &str, environment: &Environment) -> Option<Value> {
+    let (name, value) = find_definition(&environment.definitions.definitions, reference_name)?;
+    environment.used.borrow_mut().insert(name.to_string());
+    Some(value.clone())
+}
+enum Resolution {
+    Found(Value),
+    // expected to be looked up at runtime, so the placeholder is left in place
+    Runtime,
+    Missing(String),
+}
+
+// Loop variables shadow definitions, and `server/host` looks inside a loop variable `server`
+fn resolve_local(reference_name: &str, environment: &Environment) -> Option<Value> {
+    let locals = environment.locals.borrow();
+    for (local_name, local_value) in locals.iter().rev() {
+        if reference_name == local_name {
+            return Some(local_value.clone());
+        }
+        let Some(path) = reference_name
+            .strip_prefix(local_name.as_str())
+            .and_then(|rest| rest.strip_prefix('/'))
+        else {
+            continue;
+        };
+        let mut current = local_value;
+        for segment in path.split('/') {
+            let next = match current {
+                Value::Mapping(m) => m.get(segment),
+                Value::Sequence(s) => segment.parse::<usize>().ok().and_then(|i| s.get(i)),
+                _ => None,
+            };
+            current = next.unwrap_or_else(|| {
+                panic!("Loop variable \"{local_name}\" has nothing at \"{reference_name}\"")
+            });
+        }
+        return Some(current.clone());
+    }
+    None
+}
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// Definitions are expanded without any loop variables in scope
+fn expand_definition(reference_name: &str, val: Value, environment: &Environment) -> Value {
+    {
+        let mut expanding = environment.expanding.borrow_mut();
+        let cycle = expanding.iter().any(|name| name == reference_name);
+        expanding.push(reference_name.to_string());
+        if cycle {
+            panic!("Circular reference: {}", expanding.join(" -> "))
+        }
+        if expanding.len() > MAX_EXPANSION_DEPTH {
+            panic!(
+                "Variables refer to each other more than {MAX_EXPANSION_DEPTH} deep: {}",
+                expanding.join(" -> ")
+            )
+        }
+    }
+    let locals = environment.locals.take();
+    let expanded = expand(val, environment);
+    environment.locals.replace(locals);
+    environment.expanding.borrow_mut().pop();
+    expanded
+}
+
+// A variable's fully expanded value (ignoring the `/json` convention), or None if it isn't defined
+pub(crate) fn expanded_definition(name: &str, environment: &Environment) -> Option<Value> {
+    let val = environment.definitions.definitions.get(name)?.clone();
+    Some(expand_definition(name, val, environment))
+}
+
+fn resolve(reference_name: &str, environment: &Environment) -> Resolution {
+    if let Some(val) = resolve_local(reference_name, environment) {
+        return Resolution::Found(val);
+    }
     let should_be_runtime_value = environment
         .expected_runtime_lookup_prefixes
         .iter()
@@ -153,9 +260,9 @@ fn lookup(reference_name: &str, environment: &Environment) -> Option<Value> {
     match _lookup(reference_name, environment) {
         None => {
             if should_be_runtime_value {
-                None
+                Resolution::Runtime
             } else {
-                panic!("Couldn't find definition for {}", &reference_name)
+                Resolution::Missing(reference_name.to_string())
             }
         }
         Some(val) => {
@@ -165,50 +272,335 @@ fn lookup(reference_name: &str, environment: &Environment) -> Option<Value> {
                 )
             }
             if should_be_json {
-                let expanded_val = expand(val, environment);
-                if let Value::Mapping(m) = expanded_val {
-                    Some(Value::String(
-                        json_canon::to_string(&serde_json::to_value(m).unwrap()).unwrap(),
-                    ))
-                } else if let Value::String(s) = expanded_val {
-                    Some(Value::String(s))
-                } else {
-                    panic!(
-                        "Received non-mapping value for /json conversion: {:?}",
-                        &expanded_val
-                    )
+                // strings are passed through as they are, assuming they already contain JSON
+                match expand_definition(reference_name, val, environment) {
+                    Value::String(s) => Resolution::Found(Value::String(s)),
+                    expanded_val => Resolution::Found(Value::String(canonical_json(&expanded_val))),
                 }
             } else {
-                Some(expand(val, environment))
+                Resolution::Found(expand_definition(reference_name, val, environment))
             }
         }
     }
 }
+fn interpolation_value(name: &str, val: Value) -> String {
+    match val {
+        Value::Number(n) => format!("{n}"),
+        Value::String(str) => str,
+        Value::Bool(b) => format!("{b}"),
+        _ => panic!("Can't interpolate \"${{{name}}}\" ({val:?}) into a string -- try \"${{{name}/json}}\""),
+    }
+}
+
+fn try_evaluate(expr: &Expr, reference: &Reference, environment: &Environment) -> Resolution {
+    match expr {
+        Expr::Variable(name) => resolve(name, environment),
+        Expr::Literal(val) => Resolution::Found(val.clone()),
+        Expr::Interpolation(parts) => {
+            let mut string = String::new();
+            for part in parts {
+                match part {
+                    StringPart::Text(text) => string.push_str(text),
+                    StringPart::Variable(name) => match resolve(name, environment) {
+                        Resolution::Found(val) => string.push_str(&interpolation_value(name, val)),
+                        other => return other,
+                    },
+                }
+            }
+            Resolution::Found(Value::String(string))
+        }
+        Expr::Alternatives(alternatives) => {
+            let (last, others) = alternatives.split_last().unwrap();
+            for alternative in others {
+                match try_evaluate(alternative, reference, environment) {
+                    Resolution::Missing(_) => continue,
+                    other => return other,
+                }
+            }
+            try_evaluate(last, reference, environment)
+        }
+        Expr::Binary(lhs, operator, rhs) => {
+            let lhs = match try_evaluate(lhs, reference, environment) {
+                Resolution::Found(val) => val,
+                other => return other,
+            };
+            let rhs = match try_evaluate(rhs, reference, environment) {
+                Resolution::Found(val) => val,
+                other => return other,
+            };
+            match apply_operator(*operator, lhs, rhs) {
+                Ok(val) => Resolution::Found(val),
+                Err(err) => panic!("Can't evaluate \"{}\": {err}", &reference.source),
+            }
+        }
+    }
+}
+
+// The value of a reference's expression, before any filters (or None for a runtime value)
+fn evaluate(reference: &Reference, environment: &Environment) -> Option<Value> {
+    match try_evaluate(&reference.expr, reference, environment) {
+        Resolution::Found(val) => Some(val),
+        Resolution::Runtime => None,
+        Resolution::Missing(name) => panic!("Couldn't find definition for {name}"),
+    }
+}
+
+// Like `evaluate`, except that it's Missing (rather than an error) when the placeholder could just be bash arithmetic
+fn evaluate_substitution(reference: &Reference, environment: &Environment) -> Resolution {
+    match try_evaluate(&reference.expr, reference, environment) {
+        Resolution::Missing(name) if !reference.could_be_arithmetic() => {
+            panic!("Couldn't find definition for {name}")
+        }
+        resolution => resolution,
+    }
+}
+
+pub(crate) fn canonical_json(val: &Value) -> String {
+    let json = serde_json::to_value(val)
+        .unwrap_or_else(|err| panic!("Can't represent value as JSON ({err}): {val:?}"));
+    json_canon::to_string(&json).expect("Canonical JSON error")
+}
 
 lazy_static! {
-    static ref VAR_SUBSTITUTION_PATTERN: Regex = Regex::new(r"\(\(\s*([^) ]*?)\s*\)\)").unwrap();
     static ref FULL_MATCH_PATTERN: Regex =
-        Regex::new(r"\A\s*\(\(\s*([^) ]*?)\s*\)\)\s*\z").unwrap();
+        Regex::new(r"\A\s*\(\(\s*((?:[^()]|\([^()]*\))*?)\s*\)\)\s*\z").unwrap();
+}
+
+fn apply_filters(reference: &Reference, val: Value, environment: &Environment) -> Value {
+    reference.filters.iter().fold(val, |val, filter| {
+        apply_filter(&filter.name, val, &filter.args, &environment.plugins).unwrap_or_else(|err| {
+            panic!(
+                "Filter \"{}\" failed in reference \"{}\": {err}",
+                &filter.name, &reference.source
+            )
+        })
+    })
+}
+
+fn interpolated_string(reference: &Reference, val: Value) -> String {
+    match val {
+        Value::Number(n) => format!("{n}"),
+        Value::String(str) => str,
+        Value::Bool(b) => format!("{b}"),
+        _ => panic!(
+            "Attempted to interpolate non-string value \"{}\" ({val:?}) -- use \"(( {} | json ))\" to embed it as JSON",
+            &reference.source, &reference.source
+        ),
+    }
+}
+
+fn evaluate_condition(negated: bool, condition: &Reference, environment: &Environment) -> bool {
+    let val = evaluate(condition, environment).unwrap_or_else(|| {
+        panic!(
+            "Condition \"{}\" depends on a runtime value, so can't be decided now",
+            &condition.source
+        )
+    });
+    match apply_filters(condition, val, environment) {
+        Value::Bool(b) => b != negated,
+        other => panic!(
+            "Condition \"{}\" must be a boolean, got {other:?}",
+            &condition.source
+        ),
+    }
+}
+
+// The loop variable bindings for each iteration
+fn iterations(names: &[String], iterable: &Reference, environment: &Environment) -> Vec<Vec<(String, Value)>> {
+    let val = evaluate(iterable, environment).unwrap_or_else(|| {
+        panic!(
+            "Loop \"{}\" depends on a runtime value, so can't be expanded now",
+            &iterable.source
+        )
+    });
+    match (apply_filters(iterable, val, environment), names) {
+        (Value::Sequence(seq), [name]) => seq
+            .into_iter()
+            .map(|elem| vec![(name.clone(), elem)])
+            .collect(),
+        (Value::Sequence(seq), [index_name, name]) => seq
+            .into_iter()
+            .enumerate()
+            .map(|(i, elem)| vec![(index_name.clone(), Value::Number(i.into())), (name.clone(), elem)])
+            .collect(),
+        (Value::Mapping(map), [key_name, name]) => map
+            .into_iter()
+            .map(|(k, v)| vec![(key_name.clone(), k), (name.clone(), v)])
+            .collect(),
+        (Value::Mapping(_), [_]) => panic!(
+            "Loop \"{}\" is over a mapping, so needs both key and value names (\"for key, value in ...\")",
+            &iterable.source
+        ),
+        (other, _) => panic!(
+            "Loop \"{}\" must be over a sequence or mapping, got {other:?}",
+            &iterable.source
+        ),
+    }
+}
+
+fn with_locals<T>(bindings: Vec<(String, Value)>, environment: &Environment, f: impl FnOnce() -> T) -> T {
+    let count = bindings.len();
+    environment.locals.borrow_mut().extend(bindings);
+    let result = f();
+    let mut locals = environment.locals.borrow_mut();
+    let remaining = locals.len() - count;
+    locals.truncate(remaining);
+    result
+}
+
+fn render_text(nodes: &[Node], environment: &Environment, default_escape: EscapeMode, output: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Substitution(reference, text) => match evaluate_substitution(reference, environment) {
+                Resolution::Found(val) => output.push_str(
+                    &reference
+                        .escape
+                        .unwrap_or(default_escape)
+                        .escape(&interpolated_string(reference, apply_filters(reference, val, environment))),
+                ),
+                Resolution::Runtime => output.push_str(&format!("(( {} ))", &reference.source)),
+                Resolution::Missing(_) => output.push_str(text),
+            },
+            Node::If {
+                negated,
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = if evaluate_condition(*negated, condition, environment) {
+                    then
+                } else {
+                    otherwise
+                };
+                render_text(branch, environment, default_escape, output);
+            }
+            Node::For {
+                names,
+                iterable,
+                body,
+            } => {
+                for bindings in iterations(names, iterable, environment) {
+                    with_locals(bindings, environment, || {
+                        render_text(body, environment, default_escape, output)
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn substitute_string(string: &str, environment: &Environment, default_escape: EscapeMode) -> String {
+    let mut output = String::with_capacity(string.len());
+    render_text(&parse_text(string), environment, default_escape, &mut output);
+    output
+}
+
+pub(crate) fn full_match_placeholder(string: &str) -> Option<Placeholder> {
+    let captures = FULL_MATCH_PATTERN.captures(string)?;
+    parse_placeholder(captures.get(1).unwrap().as_str())
+}
+
+// The typed value of a reference, given the value of its expression
+fn filtered_value(reference: &Reference, val: Value, environment: &Environment) -> Value {
+    let val = apply_filters(reference, val, environment);
+    match reference.escape {
+        None => val,
+        Some(escape) => Value::String(escape.escape(&interpolated_string(reference, val))),
+    }
 }
 
 fn expand_string(string: String, environment: &Environment) -> Value {
-    if let Some(captures) = FULL_MATCH_PATTERN.captures(&string) {
-        let ref_name = captures.get(1).unwrap().as_str();
-        return lookup(ref_name, environment).unwrap_or(Value::String(string));
-    }
-    let substituted = VAR_SUBSTITUTION_PATTERN.replace_all(&string, |captures: &Captures| {
-        let ref_name = captures.get(1).unwrap().as_str();
-        let val = lookup(ref_name, environment);
-        match val {
-            None => format!("(( {ref_name} ))"),
-            Some(Value::Number(n)) => format!("{n}"),
-            Some(Value::String(str)) => str,
-            Some(_) => panic!(
-                "Attempted to interpolate non-string value \"{ref_name}\" ({val:?})"
-            ),
+    if let Some(Placeholder::Reference(reference)) = full_match_placeholder(&string) {
+        return match evaluate_substitution(&reference, environment) {
+            Resolution::Found(val) => filtered_value(&reference, val, environment),
+            Resolution::Runtime | Resolution::Missing(_) => Value::String(string),
+        };
+    }
+    Value::String(substitute_string(&string, environment, EscapeMode::Raw))
+}
+
+fn tag_name(tagged: &TaggedValue) -> String {
+    tagged.tag.to_string().trim_start_matches('!').to_string()
+}
+
+fn tagged_string(tagged: &TaggedValue) -> &str {
+    match &tagged.value {
+        Value::String(s) => s,
+        other => panic!("{} must be followed by a string, got {other:?}", &tagged.tag),
+    }
+}
+
+fn expand_tagged(tagged: TaggedValue, environment: &Environment) -> Value {
+    if tagged.tag == "var" {
+        // `!var db/port` is the same as `(( db/port ))`, but explicit
+        let source = tagged_string(&tagged);
+        match parse_placeholder(source) {
+            Some(Placeholder::Reference(reference)) => match evaluate(&reference, environment) {
+                Some(val) => filtered_value(&reference, val, environment),
+                None => Value::String(format!("(( {source} ))")),
+            },
+            _ => panic!("Invalid reference for !var: \"{source}\""),
         }
-    });
-    Value::String(substituted.to_string())
+    } else if is_file_tag(&tag_name(&tagged)) {
+        embed(&tag_name(&tagged), tagged_string(&tagged))
+    } else if tagged.tag == "literal" {
+        tagged.value
+    } else if tagged.tag == "delete" || tagged.tag == "replace" {
+        panic!("{} can only be used in overlays", &tagged.tag)
+    } else {
+        panic!(
+            "Unknown YAML tag {} (expected one of !var, !literal, !file, !base64file, !pemfile, !jsonfile)",
+            &tagged.tag
+        )
+    }
+}
+
+// `(( if ... ))` and `(( for ... ))` can be used as a mapping key (whose value is a mapping of entries to include)
+// or as the only key of a mapping in a sequence (whose value is the element to include)
+pub(crate) fn directive_key(key: &Value) -> Option<Directive> {
+    match full_match_placeholder(string_value(key)?.as_str())? {
+        Placeholder::Directive(Directive::Else | Directive::End) => {
+            panic!("Only (( if ... )) and (( for ... )) can be used as directives in YAML (in key {key:?})")
+        }
+        Placeholder::Directive(directive) => Some(directive),
+        Placeholder::Reference(_) => None,
+    }
+}
+
+pub(crate) fn directive_element(element: &Value) -> Option<(Directive, Value)> {
+    match element {
+        Value::Mapping(m) if m.len() == 1 => {
+            let (k, v) = m.iter().next().unwrap();
+            directive_key(k).map(|directive| (directive, v.clone()))
+        }
+        _ => None,
+    }
+}
+
+// The expanded values to include, for a directive's value
+fn expand_directive(directive: Directive, body: Value, environment: &Environment) -> Vec<Value> {
+    match directive {
+        Directive::If { negated, condition } => {
+            if evaluate_condition(negated, &condition, environment) {
+                vec![expand(body, environment)]
+            } else {
+                vec![]
+            }
+        }
+        Directive::For { names, iterable } => iterations(&names, &iterable, environment)
+            .into_iter()
+            .map(|bindings| with_locals(bindings, environment, || expand(body.clone(), environment)))
+            .collect(),
+        Directive::Else | Directive::End => unreachable!(),
+    }
+}
+
+fn describe_key(key: &Value) -> String {
+    match key {
+        Value::String(s) => format!("\"{s}\""),
+        other => format!("{other:?}"),
+    }
 }
 
 fn expand(content: Value, environment: &Environment) -> Value {
@@ -218,43 +610,83 @@ fn expand(content: Value, environment: &Environment) -> Value {
         Value::Number(a) => Value::Number(a),
         Value::String(str) => expand_string(str, environment),
         Value::Sequence(seq) => {
-            Value::Sequence(seq.into_iter().map(|v| expand(v, environment)).collect())
+            let mut expanded = Sequence::with_capacity(seq.len());
+            for v in seq {
+                match directive_element(&v) {
+                    Some((directive, body)) => {
+                        expanded.extend(expand_directive(directive, body, environment))
+                    }
+                    None => expanded.push(expand(v, environment)),
+                }
+            }
+            Value::Sequence(expanded)
         }
         Value::Mapping(map) => {
-            let mut stuff = map
-                .into_iter()
-                .map(|(k, v)| (expand(k, environment), v))
-                .collect::<Vec<_>>();
-            stuff.sort_by_key(|(k, _v)| string_value(k));
-            let stuff = stuff
-                .into_iter()
-                .map(|(k, v)| (k, expand(v, environment)))
-                .collect();
-            Value::Mapping(stuff)
-        }
-        Value::Tagged(_) => {
-            panic!("what the fuck is this?")
+            // (expanded key, description of the original key, expanded value)
+            let mut stuff = vec![];
+            for (k, v) in map {
+                let original_key = describe_key(&k);
+                match directive_key(&k) {
+                    Some(directive) => {
+                        for entries in expand_directive(directive, v, environment) {
+                            match entries {
+                                Value::Mapping(entries) => {
+                                    stuff.extend(entries.into_iter().map(|(entry_k, entry_v)| {
+                                        let description =
+                                            format!("{} (generated by {original_key})", describe_key(&entry_k));
+                                        (entry_k, description, entry_v)
+                                    }))
+                                }
+                                other => panic!(
+                                    "Entries generated by {original_key} must be a mapping, got {other:?}"
+                                ),
+                            }
+                        }
+                    }
+                    None => stuff.push((expand(k, environment), original_key, expand(v, environment))),
+                }
+            }
+            stuff.sort_by_key(|(k, _original_k, _v)| string_value(k));
+
+            let mut expanded = Mapping::with_capacity(stuff.len());
+            let mut original_keys: HashMap<Value, String> = HashMap::with_capacity(stuff.len());
+            for (k, original_key, v) in stuff {
+                if let Some(other_original_key) = original_keys.insert(k.clone(), original_key.clone()) {
+                    panic!(
+                        "Keys {other_original_key} and {original_key} both expand to {} in environment \"{}\"",
+                        describe_key(&k),
+                        &environment.name
+                    )
+                }
+                expanded.insert(k, v);
+            }
+            Value::Mapping(expanded)
         }
+        Value::Tagged(tagged) => expand_tagged(*tagged, environment),
     }
 }
 
+type PanicHook = Box<dyn Fn(&PanicHookInfo) + Sync + Send>;
+
 thread_local! {
     static CURRENT_FILE: Cell<Option<String>> = const { Cell::new(None) };
-    static DEFAULT_HOOK: Cell<Option<Box<dyn Fn(&PanicInfo)->()>>> = const { Cell::new(None) };
+    static DEFAULT_HOOK: Cell<Option<PanicHook>> = const { Cell::new(None) };
 }
 
-fn panic_hook(info: &PanicInfo) {
+fn panic_hook(info: &PanicHookInfo) {
     CURRENT_FILE.with(|f| {
         if let Some(f) = f.take().as_ref() {
             eprintln!("\nFailed to compile \"{}\"", &f);
         };
     });
     DEFAULT_HOOK.with(|def_hook| {
-        def_hook.take().map(|f| f(info) );
+        if let Some(f) = def_hook.take() {
+            f(info)
+        }
     });
 }
 
-fn with_error_catcher<T>(output_path: String, processor: &dyn Fn()->T) -> T {
+pub(crate) fn with_error_catcher<T>(output_path: String, processor: &dyn Fn()->T) -> T {
     CURRENT_FILE.with(|f| {
         f.set(Some(output_path));
     });
@@ -273,8 +705,7 @@ fn with_error_catcher<T>(output_path: String, processor: &dyn Fn()->T) -> T {
 pub(crate) fn process_text(template: &Template, environment: &Environment, output_path: String) -> String {
     with_error_catcher(output_path, &|| {
         let text = read_to_string(&template.source_path).unwrap();
-        string_value(&expand_string(text, environment))
-            .expect("Text template somehow expanded to a non-string value")
+        substitute_string(&text, environment, template.escape_mode)
     })
 }
 
@@ -284,36 +715,276 @@ pub(crate) fn process_yaml(template: &Template, environment: &Environment, outpu
         let mut content: Value =
             serde_yaml::from_reader(File::open(&template.source_path).unwrap()).unwrap();
 
+        for overlay_dir in &environment.overlay_directories {
+            let overlay_path = overlay_dir.join(filename);
+            if overlay_path.exists() {
+                apply_overlay(&mut content, &overlay_path);
+            }
+        }
         for mutation in &environment.definitions.mutations {
             if mutation.filename_pattern == filename {
                 apply_mutation(&mutation.action, &mut content);
             }
         }
         let mut content = expand(content, environment);
-        postprocess_yaml(&mut content);
+        for postprocessor in &template.postprocessors {
+            apply_postprocessor(postprocessor, &mut content, &environment.plugins, &template.source_path);
+        }
         content
     })
 }
 
-fn postprocess_yaml(_yaml_config: &mut Value) {
-    // i've left this here as an example of doing this kind of thing
-    // it can be nice to work around frameworks which have an annoying config format
-    // (unless what's annoying is that they're incompatible with json, obviously)
-
-    // however i'm commenting it out as this thing is supposed to have pure behaviour by default. fork it and build a different version for custom behaviour :)
-
-    // if let Some(profiles) = yaml_config.try_navigate(&vec!["spring".to_string(), "profiles".to_string()]) {
-    //     if let Value::Mapping(profiles) = profiles {
-    //         if let Some(Value::Sequence(active_profiles)) = profiles.get("active") {
-    //             profiles.insert(
-    //                 Value::String("active".to_string()),
-    //                 Value::String(
-    //                     active_profiles.iter().map(|prof| {
-    //                         string_value(prof).unwrap()
-    //                     }).collect::<Vec<_>>().join(", ")
-    //                 )
-    //             );
-    //         }
-    //     }
-    // }
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn value(yaml: &str) -> Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    // an environment "test" with the given definitions, where `runtime/...` is looked up at runtime
+    pub(crate) fn environment(definitions: &str) -> Environment {
+        let definitions = match value(definitions) {
+            Value::Mapping(m) => m.into_iter().map(|(k, v)| (string_value(&k).unwrap(), v)).collect(),
+            _ => HashMap::new(),
+        };
+        Environment {
+            name: "test".to_string(),
+            definitions: VariableSource {
+                name: "test".to_string(),
+                definitions,
+                overrides: HashSet::new(),
+                finals: HashSet::new(),
+                required: HashMap::new(),
+                mutations: vec![],
+                imports: vec![],
+            },
+            expected_runtime_lookup_prefixes: vec!["runtime/".to_string()],
+            overlay_directories: vec![],
+            plugins: Rc::new(Plugins::default()),
+            locals: RefCell::new(vec![]),
+            used: RefCell::new(HashSet::new()),
+            expanding: RefCell::new(vec![]),
+        }
+    }
+
+    fn expanded(definitions: &str, template: &str) -> Value {
+        expand(value(template), &environment(definitions))
+    }
+
+    fn substituted(definitions: &str, text: &str) -> String {
+        substitute_string(text, &environment(definitions), EscapeMode::Raw)
+    }
+
+    #[test]
+    fn yaml_conditionals() {
+        let definitions = "{on: true, off: false}";
+        assert_eq!(
+            expanded(definitions, "{a: 1, (( if on )): {b: 2}, (( if off )): {c: 3}}"),
+            value("{a: 1, b: 2}")
+        );
+        assert_eq!(
+            expanded(definitions, "[x, (( if on )): y, (( if not on )): z, (( if not off )): [w]]"),
+            value("[x, y, [w]]")
+        );
+    }
+
+    #[test]
+    fn yaml_loops() {
+        // (block style, since a comma would end a flow-style key)
+        let definitions = "{hosts: [a, b], tenants: {x: {db: d1}, y: {db: d2}}}";
+        assert_eq!(
+            expanded(definitions, r#"[first, (( for h in hosts )): "(( h )):80"]"#),
+            value("[first, 'a:80', 'b:80']")
+        );
+        assert_eq!(
+            expanded(definitions, "- (( for i, h in hosts )): {(( h )): (( i ))}"),
+            value("[{a: 0}, {b: 1}]")
+        );
+        assert_eq!(
+            expanded(definitions, r#"(( for name, tenant in tenants )): {"tenant_(( name ))": (( tenant/db ))}"#),
+            value("{tenant_x: d1, tenant_y: d2}")
+        );
+    }
+
+    #[test]
+    fn two_loops_in_one_mapping() {
+        // the directive keys have to differ, so the second loop uses another name
+        let template = r#"
+            (( for h in hosts )): {"(( h ))_port": 80}
+            (( for host in hosts )): {"(( host ))_tls": true}
+        "#;
+        assert_eq!(
+            expanded("{hosts: [a]}", template),
+            value("{a_port: 80, a_tls: true}")
+        );
+    }
+
+    #[test]
+    fn loop_variables_shadow_definitions() {
+        assert_eq!(
+            expanded("{h: outer, hosts: [a], x: (( h ))}", "[(( for h in hosts )): [(( h )), (( x ))]]"),
+            value("[[a, outer]]")
+        );
+    }
+
+    #[test]
+    fn bash_arithmetic() {
+        let script = "for ((i=0; i<3; i++)); do\n  echo $(( i + 1 )) $(( i < 10 )) $(( a || b ))\ndone\n";
+        assert_eq!(substituted("{}", script), script);
+        assert_eq!(expanded("{}", "[(( i + 1 )), x$((i * 2))]"), value("['(( i + 1 ))', 'x$((i * 2))']"));
+        // but it's a placeholder after all when the names are defined
+        assert_eq!(substituted("{i: 1}", "$(( i + 1 ))"), "$2");
+    }
+
+    #[test]
+    #[should_panic(expected = "Couldn't find definition for i")]
+    fn arithmetic_with_filters_is_a_placeholder() {
+        substituted("{}", "(( i + 1 | json ))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Couldn't find definition for i")]
+    fn var_tag_with_arithmetic() {
+        expanded("{}", "!var i + 1");
+    }
+
+    fn used(environment: &Environment) -> Vec<String> {
+        let mut used = environment.used.borrow().iter().cloned().collect::<Vec<_>>();
+        used.sort();
+        used
+    }
+
+    #[test]
+    fn tracks_used_definitions() {
+        let environment = environment("{a: (( b )), b: 1, features/*: true, unused: 1}");
+        expand(value("[(( a )), (( features/x )), (( features/y )), (( runtime/z ))]"), &environment);
+        // (including definitions used by other definitions, and wildcards by whatever they supplied)
+        assert_eq!(used(&environment), ["a", "b", "features/*"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Circular reference: a -> b -> a")]
+    fn circular_references() {
+        expanded("{a: (( b )), b: x(( a ))}", "(( a ))");
+    }
+
+    // v0 refers to v1, and so on, down to a value
+    fn chain(length: usize) -> String {
+        let mut definitions = (0..length - 1).map(|i| format!("v{i}: (( v{} ))\n", i + 1)).collect::<String>();
+        definitions.push_str(&format!("v{}: end", length - 1));
+        definitions
+    }
+
+    #[test]
+    fn deep_references() {
+        assert_eq!(expanded(&chain(64), "(( v0 ))"), value("end"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Variables refer to each other more than 64 deep: v0 -> v1 -> v2")]
+    fn too_deep_references() {
+        expanded(&chain(65), "(( v0 ))");
+    }
+
+    #[test]
+    fn closest_wildcard_wins() {
+        let definitions = "{a/*: outer, a/b/*: inner, a/b/c/d: exact}";
+        assert_eq!(
+            expanded(definitions, "[(( a/b/c )), (( a/b/c/e )), (( a/x )), (( a/b/c/d ))]"),
+            value("[inner, inner, outer, exact]")
+        );
+        assert_eq!(expanded("{a/*: outer, a/b/*: inner, x: (( a/b/c ))}", "(( x ))"), value("inner"));
+    }
+
+    #[test]
+    fn var_tag() {
+        let definitions = "{db/port: 5432, name: db}";
+        assert_eq!(expanded(definitions, "!var db/port"), value("5432"));
+        assert_eq!(expanded(definitions, "!var name | upper"), value("DB"));
+        // runtime values are left as placeholders
+        assert_eq!(expanded(definitions, "!var runtime/token"), value("(( runtime/token ))"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid reference for !var: \"if x\"")]
+    fn var_tag_with_directive() {
+        expanded("{x: true}", "!var if x");
+    }
+
+    #[test]
+    fn literal_tag() {
+        assert_eq!(expanded("{a: 1}", "!literal (( a ))"), value("'(( a ))'"));
+        assert_eq!(expanded("{a: 1}", "!literal {x: (( a ))}"), value("{x: '(( a ))'}"));
+    }
+
+    #[test]
+    #[should_panic(expected = "must be relative, and stay within configuration/files/")]
+    fn file_tags_are_embedded() {
+        expanded("{}", "!base64file ../secret");
+    }
+
+    #[test]
+    #[should_panic(expected = "!delete can only be used in overlays")]
+    fn delete_outside_overlay() {
+        expanded("{}", "a: !delete");
+    }
+
+    #[test]
+    #[should_panic(expected = "!replace can only be used in overlays")]
+    fn replace_outside_overlay() {
+        expanded("{}", "{a: !replace [1]}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown YAML tag !nope")]
+    fn unknown_tag() {
+        expanded("{}", "!nope x");
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Keys "(( a ))" and "x" both expand to "x" in environment "test""#)]
+    fn key_collision() {
+        expanded("{a: x}", r#"{"(( a ))": 1, x: 2}"#);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Keys "x" and "x" (generated by "(( for h in hosts ))") both expand to "x" in environment "test""#
+    )]
+    fn key_collision_with_generated_key() {
+        expanded("{hosts: [x]}", r#"{x: 1, (( for h in hosts )): {"(( h ))": 2}}"#);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Keys "a" (generated by "(( for h in hosts ))") and "a" (generated by "(( for h in hosts ))") both expand to "a""#
+    )]
+    fn key_collision_between_iterations() {
+        expanded("{hosts: [a, a]}", r#"{(( for h in hosts )): {"(( h ))": 1}}"#);
+    }
+
+    #[test]
+    #[should_panic(expected = "Entries generated by \"(( if on ))\" must be a mapping, got String(\"x\")")]
+    fn generated_entries_must_be_a_mapping() {
+        expanded("{on: true}", "{(( if on )): x}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Only (( if ... )) and (( for ... )) can be used as directives in YAML")]
+    fn else_key() {
+        expanded("{}", "{(( else )): {a: 1}}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Condition \"if n\" must be a boolean, got Number(1)")]
+    fn non_boolean_condition() {
+        expanded("{n: 1}", "{(( if n )): {a: 1}}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Loop \"for v in m\" is over a mapping, so needs both key and value names")]
+    fn mapping_loop_needs_two_names() {
+        expanded("{m: {a: 1}}", "[(( for v in m )): (( v ))]");
+    }
 }
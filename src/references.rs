@@ -0,0 +1,545 @@
+use crate::escaping::EscapeMode;
+use serde_yaml::Value;
+
+// The contents of a `(( ... ))` placeholder, e.g. `db/password | base64 | shell` or `feature/timeout || 30`
+#[derive(Debug)]
+pub(crate) struct Reference {
+    pub(crate) source: String,
+    pub(crate) expr: Expr,
+    pub(crate) filters: Vec<FilterCall>,
+    // an escaping mode can only come last, after any filters
+    pub(crate) escape: Option<EscapeMode>,
+}
+
+impl Reference {
+    // Bash arithmetic like `$(( i + 1 ))` parses as an expression too, so one with operators (and no filters, which
+    // would make it clearly meant as a placeholder) is left alone when its names aren't defined
+    pub(crate) fn could_be_arithmetic(&self) -> bool {
+        matches!(self.expr, Expr::Binary(..) | Expr::Alternatives(_)) && self.filters.is_empty() && self.escape.is_none()
+    }
+}
+
+// Block structure, e.g. `(( if feature/x ))` ... `(( else ))` ... `(( end ))`
+#[derive(Debug)]
+pub(crate) enum Directive {
+    If { negated: bool, condition: Reference },
+    // `(( for server in upstream/servers ))`, `(( for name, tenant in tenants ))`
+    For { names: Vec<String>, iterable: Reference },
+    Else,
+    End,
+}
+
+#[derive(Debug)]
+pub(crate) enum Placeholder {
+    Reference(Reference),
+    Directive(Directive),
+}
+
+#[derive(Debug)]
+pub(crate) struct FilterCall {
+    pub(crate) name: String,
+    pub(crate) args: Vec<Value>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Expr {
+    Variable(String),
+    Literal(Value),
+    // `"${host}:${port}"`
+    Interpolation(Vec<StringPart>),
+    // `a || b || "x"` -- the first alternative which is defined
+    Alternatives(Vec<Expr>),
+    // `port + 1`, `memory_mb * 1024`
+    Binary(Box<Expr>, Operator, Box<Expr>),
+}
+
+#[derive(Debug)]
+pub(crate) enum StringPart {
+    Text(String),
+    Variable(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl Operator {
+    // Operators have to be surrounded by whitespace, as names can contain `/` and `-`
+    fn from_token(token: &Token) -> Option<Operator> {
+        match token {
+            Token::Name(name) => match name.as_str() {
+                "+" => Some(Operator::Add),
+                "-" => Some(Operator::Subtract),
+                "*" => Some(Operator::Multiply),
+                "/" => Some(Operator::Divide),
+                "%" => Some(Operator::Remainder),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub(crate) fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Remainder => "%",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Pipe,
+    OrOr,
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize(content: &str) -> Option<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = content.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '|' {
+            chars.next();
+            if let Some((_, '|')) = chars.peek() {
+                chars.next();
+                tokens.push(Token::OrOr);
+            } else {
+                tokens.push(Token::Pipe);
+            }
+        } else if c == '(' || c == ')' || c == ',' {
+            chars.next();
+            tokens.push(match c {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                _ => Token::Comma,
+            });
+        } else if c == '"' {
+            // JSON string syntax
+            chars.next();
+            let mut escaped = false;
+            let end = loop {
+                let (i, c) = chars.next()?;
+                match c {
+                    '"' if !escaped => break i,
+                    '\\' if !escaped => escaped = true,
+                    _ => escaped = false,
+                }
+            };
+            tokens.push(Token::Str(serde_json::from_str(&content[start..=end]).ok()?));
+        } else {
+            let mut end = content.len();
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_whitespace() || "|\"(),".contains(c) {
+                    end = i;
+                    break;
+                }
+                chars.next();
+            }
+            tokens.push(Token::Name(content[start..end].to_string()));
+        }
+    }
+    Some(tokens)
+}
+
+// `${name}` in a string literal is replaced by the variable's value, and `$${` is a literal `${`
+fn interpolation(s: &str) -> Vec<StringPart> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            text.push_str(&rest[..i]);
+            text.push('{');
+            rest = &rest[i + 2..];
+            continue;
+        }
+        text.push_str(&rest[..i]);
+        let Some(end) = rest[i..].find('}') else {
+            panic!("Unterminated \"${{\" in string \"{s}\" (use \"$${{\" for a literal \"${{\")")
+        };
+        let name = rest[i + 2..i + end].trim();
+        if name.is_empty() {
+            panic!("Empty \"${{}}\" in string \"{s}\"")
+        }
+        parts.push(StringPart::Text(std::mem::take(&mut text)));
+        parts.push(StringPart::Variable(name.to_string()));
+        rest = &rest[i + end + 1..];
+    }
+    text.push_str(rest);
+    parts.push(StringPart::Text(text));
+    parts
+}
+
+fn operand(token: Token) -> Option<Expr> {
+    match token {
+        Token::Str(s) => match interpolation(&s) {
+            parts if parts.len() > 1 => Some(Expr::Interpolation(parts)),
+            mut parts => match parts.pop() {
+                Some(StringPart::Text(text)) => Some(Expr::Literal(Value::String(text))),
+                _ => unreachable!(),
+            },
+        },
+        Token::Name(name) => match name.as_str() {
+            "true" => Some(Expr::Literal(Value::Bool(true))),
+            "false" => Some(Expr::Literal(Value::Bool(false))),
+            "null" => Some(Expr::Literal(Value::Null)),
+            _ if name.starts_with(|c: char| c.is_ascii_digit() || c == '-') => {
+                match serde_yaml::from_str::<Value>(&name) {
+                    Ok(number @ Value::Number(_)) => Some(Expr::Literal(number)),
+                    _ => Some(Expr::Variable(name)),
+                }
+            }
+            _ => Some(Expr::Variable(name)),
+        },
+        _ => None,
+    }
+}
+
+pub(crate) fn is_modifier_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn local_name(token: Token) -> Option<String> {
+    match token {
+        Token::Name(name) if is_modifier_name(&name) => Some(name),
+        _ => None,
+    }
+}
+
+type Tokens = std::iter::Peekable<std::vec::IntoIter<Token>>;
+
+// Returns None if the contents don't look like a placeholder at all (e.g. `(( i < 10 ))`), in which case the
+// placeholder is left as it is. Something like bash's `(( i + 1 ))` *is* an expression (see `could_be_arithmetic`).
+pub(crate) fn parse_placeholder(content: &str) -> Option<Placeholder> {
+    let mut tokens = tokenize(content)?.into_iter().peekable();
+
+    let keyword = match tokens.peek() {
+        Some(Token::Name(name)) => name.as_str(),
+        _ => "",
+    };
+    let placeholder = match keyword {
+        "if" => {
+            tokens.next();
+            let negated = tokens.next_if_eq(&Token::Name("not".to_string())).is_some();
+            let condition = parse_reference(&mut tokens, content)?;
+            if condition.escape.is_some() {
+                panic!("Escaping mode in condition \"{content}\"")
+            }
+            Placeholder::Directive(Directive::If { negated, condition })
+        }
+        "for" => {
+            tokens.next();
+            let mut names = vec![local_name(tokens.next()?)?];
+            if tokens.next_if_eq(&Token::Comma).is_some() {
+                names.push(local_name(tokens.next()?)?);
+            }
+            if tokens.next()? != Token::Name("in".to_string()) {
+                return None;
+            }
+            let iterable = parse_reference(&mut tokens, content)?;
+            if iterable.escape.is_some() {
+                panic!("Escaping mode in loop \"{content}\"")
+            }
+            Placeholder::Directive(Directive::For { names, iterable })
+        }
+        "else" => {
+            tokens.next();
+            Placeholder::Directive(Directive::Else)
+        }
+        "end" => {
+            tokens.next();
+            Placeholder::Directive(Directive::End)
+        }
+        _ => Placeholder::Reference(parse_reference(&mut tokens, content)?),
+    };
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some(placeholder)
+}
+
+fn parse_alternatives(tokens: &mut Tokens) -> Option<Expr> {
+    let mut alternatives = vec![parse_sum(tokens)?];
+    while tokens.next_if_eq(&Token::OrOr).is_some() {
+        alternatives.push(parse_sum(tokens)?);
+    }
+    if alternatives.len() == 1 {
+        alternatives.pop()
+    } else {
+        Some(Expr::Alternatives(alternatives))
+    }
+}
+
+fn next_operator(tokens: &mut Tokens, operators: &[Operator]) -> Option<Operator> {
+    let operator = Operator::from_token(tokens.peek()?).filter(|op| operators.contains(op))?;
+    tokens.next();
+    Some(operator)
+}
+
+fn parse_sum(tokens: &mut Tokens) -> Option<Expr> {
+    let mut expr = parse_product(tokens)?;
+    while let Some(operator) = next_operator(tokens, &[Operator::Add, Operator::Subtract]) {
+        expr = Expr::Binary(Box::new(expr), operator, Box::new(parse_product(tokens)?));
+    }
+    Some(expr)
+}
+
+fn parse_product(tokens: &mut Tokens) -> Option<Expr> {
+    let mut expr = parse_primary(tokens)?;
+    while let Some(operator) = next_operator(tokens, &[Operator::Multiply, Operator::Divide, Operator::Remainder]) {
+        expr = Expr::Binary(Box::new(expr), operator, Box::new(parse_primary(tokens)?));
+    }
+    Some(expr)
+}
+
+fn parse_primary(tokens: &mut Tokens) -> Option<Expr> {
+    if tokens.next_if_eq(&Token::LParen).is_some() {
+        let expr = parse_alternatives(tokens)?;
+        return match tokens.next()? {
+            Token::RParen => Some(expr),
+            _ => None,
+        };
+    }
+    let token = tokens.next()?;
+    if Operator::from_token(&token).is_some() {
+        return None;
+    }
+    operand(token)
+}
+
+fn parse_reference(tokens: &mut Tokens, content: &str) -> Option<Reference> {
+    let expr = parse_alternatives(tokens)?;
+
+    // once there's a `|`, this can only be a filter pipeline, so anything wrong with it is an error
+    let mut modifiers = vec![];
+    while tokens.next_if_eq(&Token::Pipe).is_some() {
+        let name = match tokens.next() {
+            Some(Token::Name(name)) if is_modifier_name(&name) => name,
+            Some(Token::Name(name)) => panic!("Invalid filter name \"{name}\", in reference \"{content}\""),
+            _ => panic!("Missing filter name after \"|\", in reference \"{content}\""),
+        };
+        let mut args = vec![];
+        if tokens.next_if_eq(&Token::LParen).is_some() && tokens.next_if_eq(&Token::RParen).is_none() {
+            loop {
+                match tokens.next().and_then(operand) {
+                    Some(Expr::Literal(val)) => args.push(val),
+                    Some(_) => panic!("Filter arguments must be literals, in reference \"{content}\""),
+                    None => panic!("Invalid arguments for filter \"{name}\", in reference \"{content}\""),
+                }
+                match tokens.next() {
+                    Some(Token::Comma) => continue,
+                    Some(Token::RParen) => break,
+                    _ => panic!("Invalid arguments for filter \"{name}\", in reference \"{content}\""),
+                }
+            }
+        }
+        modifiers.push(FilterCall { name, args });
+    }
+    if !modifiers.is_empty() && tokens.peek().is_some() {
+        panic!("Unexpected text after filters, in reference \"{content}\"")
+    }
+
+    let mut escape = None;
+    if let Some(last) = modifiers.last() {
+        if let Some(mode) = EscapeMode::from_name(&last.name) {
+            if !last.args.is_empty() {
+                panic!("Escaping mode \"{}\" takes no arguments, in reference \"{content}\"", &last.name)
+            }
+            escape = Some(mode);
+            modifiers.pop();
+        }
+    }
+    for modifier in &modifiers {
+        if EscapeMode::from_name(&modifier.name).is_some() {
+            panic!("Escaping mode \"{}\" must come last, in reference \"{content}\"", &modifier.name)
+        }
+    }
+
+    Some(Reference {
+        source: content.to_string(),
+        expr,
+        filters: modifiers,
+        escape,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an expression, fully parenthesised
+    fn show(expr: &Expr) -> String {
+        match expr {
+            Expr::Variable(name) => name.clone(),
+            Expr::Literal(value) => serde_json::to_string(value).unwrap(),
+            Expr::Interpolation(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    StringPart::Text(text) => format!("{text:?}"),
+                    StringPart::Variable(name) => format!("${{{name}}}"),
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            Expr::Alternatives(alternatives) => {
+                let alternatives = alternatives.iter().map(show).collect::<Vec<_>>();
+                format!("[{}]", alternatives.join(" || "))
+            }
+            Expr::Binary(lhs, operator, rhs) => format!("({} {} {})", show(lhs), operator.symbol(), show(rhs)),
+        }
+    }
+
+    fn reference(content: &str) -> Reference {
+        match parse_placeholder(content) {
+            Some(Placeholder::Reference(reference)) => reference,
+            other => panic!("{content:?} parsed as {other:?}"),
+        }
+    }
+
+    fn parsed(content: &str) -> String {
+        show(&reference(content).expr)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parsed("a + b * c"), "(a + (b * c))");
+        assert_eq!(parsed("a * b - c % 2"), "((a * b) - (c % 2))");
+        assert_eq!(parsed("a - b - c"), "((a - b) - c)");
+        assert_eq!(parsed("a / b / 2"), "((a / b) / 2)");
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(parsed("(a + b) * c"), "((a + b) * c)");
+        assert_eq!(parsed("a - (b - c)"), "(a - (b - c))");
+        assert_eq!(parsed("((a))"), "a");
+    }
+
+    #[test]
+    fn operators_need_whitespace() {
+        assert_eq!(parsed("app-name"), "app-name");
+        assert_eq!(parsed("db/port"), "db/port");
+        assert_eq!(parsed("-1 + 2"), "(-1 + 2)");
+    }
+
+    #[test]
+    fn alternatives_with_arithmetic() {
+        assert_eq!(parsed("a || b + 1"), "[a || (b + 1)]");
+        assert_eq!(parsed("a * 2 || 10"), "[(a * 2) || 10]");
+        assert_eq!(parsed("(a || 1) * 2"), "([a || 1] * 2)");
+    }
+
+    #[test]
+    fn interpolation() {
+        assert_eq!(parsed(r#""${host}:${ port }""#), r#""" ${host} ":" ${port} """#);
+        assert_eq!(parsed(r#""plain""#), r#""plain""#);
+    }
+
+    #[test]
+    fn escaped_interpolation() {
+        assert_eq!(parsed(r#""$${host}""#), r#""${host}""#);
+        assert_eq!(parsed(r#""$${a} ${b}""#), r#""${a} " ${b} """#);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unterminated \"${\" in string \"x ${host\"")]
+    fn unterminated_interpolation() {
+        parse_placeholder(r#""x ${host""#);
+    }
+
+    #[test]
+    #[should_panic(expected = "Empty \"${}\"")]
+    fn empty_interpolation() {
+        parse_placeholder(r#""x ${ }""#);
+    }
+
+    fn filters(content: &str) -> Vec<(String, Vec<Value>)> {
+        reference(content)
+            .filters
+            .into_iter()
+            .map(|filter| (filter.name, filter.args))
+            .collect()
+    }
+
+    #[test]
+    fn filter_pipeline() {
+        assert_eq!(
+            filters(r#"hosts | join(", ") | upper"#),
+            [("join".to_string(), vec![Value::from(", ")]), ("upper".to_string(), vec![])]
+        );
+        assert_eq!(filters("name | trim()"), [("trim".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn escaping_mode_comes_off_the_pipeline() {
+        match parse_placeholder("db/password | base64 | shell") {
+            Some(Placeholder::Reference(reference)) => {
+                assert_eq!(reference.filters.len(), 1);
+                assert_eq!(reference.escape, Some(EscapeMode::Shell));
+            }
+            other => panic!("parsed as {other:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid filter name \"my-filter\", in reference \"db/host | my-filter\"")]
+    fn invalid_filter_name() {
+        parse_placeholder("db/host | my-filter");
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing filter name after \"|\", in reference \"db/host | \"")]
+    fn missing_filter_name() {
+        parse_placeholder("db/host | ");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid arguments for filter \"join\", in reference \"hosts | join(, )\"")]
+    fn invalid_filter_arguments() {
+        parse_placeholder("hosts | join(, )");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid arguments for filter \"join\"")]
+    fn unclosed_filter_arguments() {
+        parse_placeholder(r#"hosts | join("," "#);
+    }
+
+    #[test]
+    #[should_panic(expected = "Filter arguments must be literals")]
+    fn variable_filter_arguments() {
+        parse_placeholder("hosts | join(separator)");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected text after filters, in reference \"name | upper lower\"")]
+    fn text_after_filters() {
+        parse_placeholder("name | upper lower");
+    }
+
+    #[test]
+    fn bash_arithmetic() {
+        // simple arithmetic is an expression (left alone if `i` isn't defined), anything else isn't a placeholder
+        assert_eq!(parsed(" i + 1 "), "(i + 1)");
+        assert!(reference(" i + 1 ").could_be_arithmetic());
+        assert!(reference(" a || b ").could_be_arithmetic());
+        assert!(!reference(" i ").could_be_arithmetic());
+        assert!(!reference(" i + 1 | json ").could_be_arithmetic());
+        assert!(parse_placeholder(" i < 10 ").is_none());
+        assert!(parse_placeholder(" i + ").is_none());
+        assert!(parse_placeholder(" + 1 ").is_none());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::references::{parse_placeholder, Directive, Placeholder, Reference};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // `\((` is an escaped, literal `((`
+    // (allowing a single level of nested parentheses, for filter arguments)
+    static ref VAR_SUBSTITUTION_PATTERN: Regex =
+        Regex::new(r"\\\(\(|\(\(\s*((?:[^()]|\([^()]*\))*?)\s*\)\)").unwrap();
+}
+
+#[derive(Debug)]
+pub(crate) enum Node {
+    Text(String),
+    // (along with the placeholder as written, in case it turns out not to be one)
+    Substitution(Reference, String),
+    If {
+        negated: bool,
+        condition: Reference,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        names: Vec<String>,
+        iterable: Reference,
+        body: Vec<Node>,
+    },
+}
+
+enum Piece {
+    Text(String),
+    Substitution(Reference, String),
+    Directive(Directive),
+}
+
+// If a directive is alone on its line, the whole line is dropped rather than leaving a blank line behind.
+fn standalone_line(text: &str, start: usize, end: usize, min_start: usize) -> Option<(usize, usize)> {
+    let line_start = text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = text[end..].find('\n').map(|i| end + i + 1).unwrap_or(text.len());
+    if line_start < min_start
+        || !text[line_start..start].trim().is_empty()
+        || !text[end..line_end].trim().is_empty()
+    {
+        return None;
+    }
+    Some((line_start, line_end))
+}
+
+fn pieces(text: &str) -> Vec<Piece> {
+    let mut pieces = vec![];
+    let mut last_end = 0;
+    for captures in VAR_SUBSTITUTION_PATTERN.captures_iter(text) {
+        let whole = captures.get(0).unwrap();
+        let (mut start, mut end) = (whole.start(), whole.end());
+        let piece = match captures.get(1).map(|c| parse_placeholder(c.as_str())) {
+            // escaped `((`
+            None => Piece::Text("((".to_string()),
+            // doesn't look like a placeholder, so leave it alone
+            Some(None) => Piece::Text(whole.as_str().to_string()),
+            Some(Some(Placeholder::Reference(reference))) => {
+                Piece::Substitution(reference, whole.as_str().to_string())
+            }
+            Some(Some(Placeholder::Directive(directive))) => {
+                if let Some(line) = standalone_line(text, start, end, last_end) {
+                    (start, end) = line;
+                }
+                Piece::Directive(directive)
+            }
+        };
+        pieces.push(Piece::Text(text[last_end..start].to_string()));
+        pieces.push(piece);
+        last_end = end;
+    }
+    pieces.push(Piece::Text(text[last_end..].to_string()));
+    pieces
+}
+
+// Whether there's anything left to substitute (e.g. a runtime value, once everything else has been expanded)
+pub(crate) fn has_placeholders(text: &str) -> bool {
+    pieces(text).iter().any(|piece| !matches!(piece, Piece::Text(_)))
+}
+
+// Returns the nodes up to the directive which ended the block (if any)
+fn parse_block(pieces: &mut impl Iterator<Item = Piece>) -> (Vec<Node>, Option<Directive>) {
+    let mut nodes = vec![];
+    while let Some(piece) = pieces.next() {
+        match piece {
+            Piece::Text(text) => nodes.push(Node::Text(text)),
+            Piece::Substitution(reference, text) => nodes.push(Node::Substitution(reference, text)),
+            Piece::Directive(Directive::If { negated, condition }) => {
+                let (then, ended_by) = parse_block(pieces);
+                let otherwise = match ended_by {
+                    Some(Directive::End) => vec![],
+                    Some(Directive::Else) => match parse_block(pieces) {
+                        (otherwise, Some(Directive::End)) => otherwise,
+                        (_, Some(Directive::Else)) => {
+                            panic!("Unexpected second (( else )) for \"(( {} ))\"", &condition.source)
+                        }
+                        _ => panic!("Missing (( end )) for \"(( {} ))\"", &condition.source),
+                    },
+                    _ => panic!("Missing (( end )) for \"(( {} ))\"", &condition.source),
+                };
+                nodes.push(Node::If {
+                    negated,
+                    condition,
+                    then,
+                    otherwise,
+                });
+            }
+            Piece::Directive(Directive::For { names, iterable }) => match parse_block(pieces) {
+                (body, Some(Directive::End)) => nodes.push(Node::For {
+                    names,
+                    iterable,
+                    body,
+                }),
+                (_, Some(Directive::Else)) => {
+                    panic!("Unexpected (( else )) in for loop \"(( {} ))\"", &iterable.source)
+                }
+                _ => panic!("Missing (( end )) for \"(( {} ))\"", &iterable.source),
+            },
+            Piece::Directive(directive) => return (nodes, Some(directive)),
+        }
+    }
+    (nodes, None)
+}
+
+pub(crate) fn parse_text(text: &str) -> Vec<Node> {
+    match parse_block(&mut pieces(text).into_iter()) {
+        (nodes, None) => nodes,
+        (_, Some(Directive::Else)) => panic!("Unexpected (( else )) without a matching (( if ))"),
+        (_, Some(_)) => panic!("Unexpected (( end )) without a matching (( if )) or (( for ))"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a compact description of the parsed nodes
+    fn show(nodes: &[Node]) -> String {
+        nodes
+            .iter()
+            .map(|node| match node {
+                Node::Text(text) => format!("{text:?}"),
+                Node::Substitution(reference, _) => format!("<{}>", &reference.source),
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                    ..
+                } => format!("<{}> [{}] else [{}]", &condition.source, show(then), show(otherwise)),
+                Node::For { iterable, body, .. } => format!("<{}> [{}]", &iterable.source, show(body))
+            })
+            .filter(|node| node != "\"\"")
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn parsed(text: &str) -> String {
+        show(&parse_text(text))
+    }
+
+    #[test]
+    fn substitutions() {
+        assert_eq!(parsed("host=(( db/host ))\n"), r#""host=" <db/host> "\n""#);
+        assert_eq!(parsed("((a))((b))"), "<a> <b>");
+    }
+
+    #[test]
+    fn escaped_and_unrecognised_placeholders() {
+        assert_eq!(parsed(r"echo \(( i++ ))"), r#""echo " "((" " i++ ))""#);
+        assert_eq!(parsed("$(( i < 10 ))"), r#""$" "(( i < 10 ))""#);
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(parsed("(( if a ))x(( end ))"), r#"<if a> ["x"] else []"#);
+        assert_eq!(parsed("(( if not a ))x(( else ))y(( end ))"), r#"<if not a> ["x"] else ["y"]"#);
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(parsed("(( for h in hosts ))<(( h ))>(( end ))"), r#"<for h in hosts> ["<" <h> ">"]"#);
+        assert_eq!(parsed("(( for k, v in map ))(( k ))(( end ))"), "<for k, v in map> [<k>]");
+    }
+
+    #[test]
+    fn nesting() {
+        assert_eq!(
+            parsed("(( for h in hosts ))(( if a ))(( h ))(( else ))-(( end ))(( end ))"),
+            r#"<for h in hosts> [<if a> [<h>] else ["-"]]"#
+        );
+        assert_eq!(
+            parsed("(( if a ))(( if b ))x(( end ))(( else ))(( for i in c ))y(( end ))(( end ))"),
+            r#"<if a> [<if b> ["x"] else []] else [<for i in c> ["y"]]"#
+        );
+    }
+
+    #[test]
+    fn removes_standalone_directive_lines() {
+        assert_eq!(
+            parsed("a\n  (( if x ))  \nb\n(( else ))\nc\n(( end ))\nd\n"),
+            r#""a\n" <if x> ["b\n"] else ["c\n"] "d\n""#
+        );
+        assert_eq!(parsed("(( for i in l ))\n(( i ))\n(( end ))"), r#"<for i in l> [<i> "\n"]"#);
+    }
+
+    #[test]
+    fn keeps_lines_with_other_text() {
+        assert_eq!(
+            parsed("a (( if x ))b(( end )) c\n"),
+            r#""a " <if x> ["b"] else [] " c\n""#
+        );
+        // (the substitution keeps its line, so the directive after it isn't alone on its line)
+        assert_eq!(parsed("(( y ))\n(( if x ))b\n(( end ))\n"), r#"<y> "\n" <if x> ["b\n"] else []"#);
+    }
+
+    #[test]
+    fn finds_placeholders() {
+        assert!(has_placeholders("http://(( runtime/host )):80"));
+        assert!(!has_placeholders(r"http://\(( x )):80 $(( i < 10 ))"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing (( end )) for \"(( if a ))\"")]
+    fn missing_end() {
+        parse_text("(( if a ))x");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected (( else )) in for loop \"(( for h in hosts ))\"")]
+    fn else_in_loop() {
+        parse_text("(( for h in hosts ))x(( else ))y(( end ))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected second (( else )) for \"(( if a ))\"")]
+    fn second_else() {
+        parse_text("(( if a ))x(( else ))y(( else ))z(( end ))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected (( else )) without a matching (( if ))")]
+    fn else_without_if() {
+        parse_text("x(( else ))y");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected (( end )) without a matching (( if )) or (( for ))")]
+    fn end_without_if() {
+        parse_text("x(( end ))");
+    }
+}
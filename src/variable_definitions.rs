@@ -1,11 +1,15 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::path::Path;
+use std::rc::Rc;
 
+use clap::ValueEnum;
 use serde_yaml::{Mapping, Value};
 
+use crate::processing::find_definition;
+
 type ValuePath = Vec<String>;
 
 #[derive(Debug, Clone)]
@@ -22,23 +26,107 @@ pub(crate) enum MutationAction {
 
 #[derive(Debug)]
 pub(crate) struct VariableSource {
+    // the variable file's name, for error messages
+    pub name: String,
     pub definitions: HashMap<String, Value>,
+    // definitions marked `!override`, meaning they're expected to replace an earlier file's
+    pub overrides: HashSet<String>,
+    // definitions marked `!final`, which no later file may redefine (for `x/*`, nothing under `x/` either)
+    pub finals: HashSet<String>,
+    // variables declared `!required "description"`, which some file has to define
+    pub required: HashMap<String, String>,
     pub mutations: Vec<Mutation>,
+    // other variable files (by name, like in `configuration.variables`) which this one builds on
+    pub imports: Vec<String>,
+}
+
+pub(crate) fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Default)]
+struct ParsedDefs {
+    definitions: HashMap<String, Value>,
+    // which top-level key each name came from, to report conflicts
+    origins: HashMap<String, String>,
+    overrides: HashSet<String>,
+    finals: HashSet<String>,
+    required: HashMap<String, String>,
+}
+
+// Strips (and records) any marker tag, e.g. `db/host: !override prod-db` or `tls/min_version: !final "1.2"`.
+// A `!required "description"` declaration has no value at all.
+fn unmarked_value(name: &str, value: Value, defs: &mut ParsedDefs) -> io::Result<Option<Value>> {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == "override" => {
+            defs.overrides.insert(name.to_string());
+            Ok(Some(tagged.value))
+        }
+        Value::Tagged(tagged) if tagged.tag == "final" => {
+            defs.finals.insert(name.to_string());
+            Ok(Some(tagged.value))
+        }
+        Value::Tagged(tagged) if tagged.tag == "required" => {
+            let description = match tagged.value {
+                Value::Null => String::new(),
+                Value::String(description) => description,
+                _ => return Err(invalid(format!("!required for \"{name}\" takes a description string"))),
+            };
+            defs.required.insert(name.to_string(), description);
+            Ok(None)
+        }
+        other => Ok(Some(other)),
+    }
 }
 
-fn parse_defs(input: Mapping) -> io::Result<HashMap<String, Value>> {
-    let mut map: HashMap<String, Value> = HashMap::with_capacity(input.len());
+// A key ending in `/` groups nested definitions, so `db/: {host: x, primary/: {port: 1}}` defines `db/host` and
+// `db/primary/port`.
+fn flatten_defs(input: Mapping, prefix: &str, origin: Option<&str>, defs: &mut ParsedDefs) -> io::Result<()> {
     for (key, value) in input {
-        if let Value::String(string) = key {
-            map.insert(string, value);
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Non-string key in variable definitions",
-            ));
+        let Value::String(key) = key else {
+            return Err(invalid("Non-string key in variable definitions".to_string()));
+        };
+        let name = format!("{prefix}{key}");
+        let origin = origin.unwrap_or(&key).to_string();
+        if key.ends_with('/') {
+            if key.trim_end_matches('/').is_empty() || key[..key.len() - 1].ends_with('/') {
+                return Err(invalid(format!("Invalid group name \"{name}\" in variable definitions")));
+            }
+            match value {
+                Value::Mapping(nested) => flatten_defs(nested, &name, Some(&origin), defs)?,
+                _ => {
+                    return Err(invalid(format!(
+                        "\"{name}\" ends with a slash, so must be a mapping of nested definitions"
+                    )))
+                }
+            }
+            continue;
+        }
+        if let Some(previous) = defs.origins.insert(name.clone(), origin.clone()) {
+            let describe = |origin: &str| {
+                if origin == name {
+                    format!("\"{name}\"")
+                } else {
+                    format!("nested under \"{origin}\"")
+                }
+            };
+            return Err(invalid(format!(
+                "Variable \"{name}\" is defined more than once ({} and {})",
+                describe(&previous),
+                describe(&origin)
+            )));
+        }
+        if let Some(value) = unmarked_value(&name, value, defs)? {
+            defs.definitions.insert(name, value);
         }
     }
-    Ok(map)
+    Ok(())
+}
+
+fn parse_defs(input: Mapping) -> io::Result<ParsedDefs> {
+    let mut defs = ParsedDefs::default();
+    flatten_defs(input, "", None, &mut defs)?;
+    Ok(defs)
 }
 
 pub(crate) fn string_value(input: &Value) -> Option<String> {
@@ -62,9 +150,9 @@ fn _parse_value_path(nested_input: &Value) -> Option<Vec<String>> {
             vec
         })
     } else if let Value::Null = nested_input {
-        return Some(vec![]);
+        Some(vec![])
     } else {
-        return None;
+        None
     }
 }
 
@@ -135,21 +223,162 @@ fn remove_mutations(input: &mut Mapping) -> io::Result<Vec<Mutation>> {
     ))
 }
 
-pub(crate) fn load(path: &Path) -> io::Result<VariableSource> {
-    let input_file = File::open(path)?;
+fn remove_imports(input: &mut Mapping) -> io::Result<Vec<String>> {
+    match input.remove("imports") {
+        None => Ok(vec![]),
+        Some(Value::Sequence(imports)) => imports
+            .iter()
+            .map(|import| string_value(import).ok_or_else(|| invalid(format!("Dodgy import: {import:?}"))))
+            .collect(),
+        Some(_) => Err(invalid("Imports must be a list of variable file names".to_string())),
+    }
+}
+
+pub(crate) fn load(name: &str, path: &Path) -> io::Result<VariableSource> {
+    let input_file = File::open(path).map_err(|err| io::Error::new(err.kind(), format!("{err}: {path:?}")))?;
     let mut input: Mapping = serde_yaml::from_reader(input_file).unwrap();
 
-    let mutations = remove_mutations(&mut input)?;
+    let in_file = |err: io::Error| invalid(format!("{err}, in {path:?}"));
+    let mutations = remove_mutations(&mut input).map_err(in_file)?;
+    let imports = remove_imports(&mut input).map_err(in_file)?;
+
+    let defs = parse_defs(input).map_err(in_file)?;
 
     Ok(VariableSource {
-        definitions: parse_defs(input)?,
+        name: name.to_string(),
+        definitions: defs.definitions,
+        overrides: defs.overrides,
+        finals: defs.finals,
+        required: defs.required,
         mutations,
+        imports,
     })
 }
 
-pub(crate) fn combine(sources: Vec<&VariableSource>) -> VariableSource {
+// Adds a variable file to `sources`, after its imports (recursively). A file is only included the first time
+// it's reached, so the order is deterministic and later files override earlier ones as usual.
+pub(crate) fn load_with_imports(
+    name: &str,
+    load: &mut dyn FnMut(&str) -> io::Result<Rc<VariableSource>>,
+    importers: &mut Vec<String>,
+    sources: &mut Vec<(String, Rc<VariableSource>)>,
+) -> io::Result<()> {
+    if importers.iter().any(|importer| importer == name) {
+        return Err(invalid(format!(
+            "Variable files import each other: {} -> {name}",
+            importers.join(" -> ")
+        )));
+    }
+    if sources.iter().any(|(included, _)| included == name) {
+        return Ok(());
+    }
+    let source = load(name)?;
+    importers.push(name.to_string());
+    for import in &source.imports {
+        load_with_imports(import, load, importers, sources)?;
+    }
+    importers.pop();
+    sources.push((name.to_string(), source));
+    Ok(())
+}
+
+// What to do when a later variable file redefines a variable
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub(crate) enum OverridePolicy {
+    Silent,
+    // list every override
+    Report,
+    // fail unless the redefinition is marked `!override`
+    RequireMarker,
+}
+
+// A later variable file redefining a variable
+#[derive(Debug)]
+pub(crate) struct Override {
+    pub(crate) name: String,
+    pub(crate) previous_source: String,
+    pub(crate) source: String,
+    pub(crate) marked: bool,
+}
+
+// The overrides which `combine` would silently apply, in order
+pub(crate) fn overrides(sources: &[&VariableSource]) -> Vec<Override> {
+    let mut defined_by: HashMap<&str, &str> = HashMap::new();
+    let mut overrides = vec![];
+    for source in sources {
+        let mut names = source.definitions.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            if let Some(previous_source) = defined_by.insert(name, &source.name) {
+                overrides.push(Override {
+                    name: name.to_string(),
+                    previous_source: previous_source.to_string(),
+                    source: source.name.to_string(),
+                    marked: source.overrides.contains(name),
+                });
+            }
+        }
+    }
+    overrides
+}
+
+pub(crate) fn check_overrides(env_name: &str, sources: &[&VariableSource], policy: OverridePolicy) -> io::Result<()> {
+    if policy == OverridePolicy::Silent {
+        return Ok(());
+    }
+    let overrides = overrides(sources);
+    for source in sources {
+        let mut marked = source.overrides.iter().collect::<Vec<_>>();
+        marked.sort();
+        for name in marked {
+            if !overrides.iter().any(|o| &o.name == name && o.source == source.name) {
+                eprintln!(
+                    "WARN: \"{name}\" in {} is marked !override, but doesn't override anything in environment \"{env_name}\"",
+                    &source.name
+                );
+            }
+        }
+    }
+    let mut unmarked = vec![];
+    for o in &overrides {
+        let description = format!("\"{}\" from {} is overridden by {}", &o.name, &o.previous_source, &o.source);
+        if policy == OverridePolicy::Report {
+            eprintln!("{env_name}: {description}{}", if o.marked { "" } else { " (unmarked)" });
+        } else if !o.marked {
+            unmarked.push(description);
+        }
+    }
+    if !unmarked.is_empty() {
+        return Err(invalid(format!(
+            "Environment \"{env_name}\" has overrides which aren't marked !override: {}",
+            unmarked.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+fn locks(final_name: &str, name: &str) -> bool {
+    match final_name.strip_suffix('*') {
+        Some(prefix) if prefix.ends_with('/') => name.starts_with(prefix),
+        _ => name == final_name,
+    }
+}
+
+pub(crate) fn combine(environment_name: &str, sources: Vec<&VariableSource>) -> Result<VariableSource, String> {
     let mut all_defs: HashMap<String, Value> = HashMap::new();
+    let mut finals: Vec<(&str, &str)> = vec![];
     for source in sources.iter() {
+        let mut names = source.definitions.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            if let Some((final_name, final_source)) = finals.iter().find(|(final_name, _)| locks(final_name, name)) {
+                return Err(format!(
+                    "\"{final_name}\" is final in {final_source}, so {} can't redefine \"{name}\"",
+                    &source.name
+                ));
+            }
+        }
+        finals.extend(source.finals.iter().map(|name| (name.as_str(), source.name.as_str())));
         all_defs.extend(
             source
                 .definitions
@@ -157,8 +386,258 @@ pub(crate) fn combine(sources: Vec<&VariableSource>) -> VariableSource {
                 .map(|(k, v)| (k.clone(), v.clone())),
         );
     }
-    VariableSource {
+    all_defs.insert("environment/name".to_string(), Value::String(environment_name.to_string()));
+
+    let mut missing = sources
+        .iter()
+        .flat_map(|source| source.required.iter().map(move |(name, description)| (name, description, &source.name)))
+        .filter(|(name, _, _)| find_definition(&all_defs, name).is_none())
+        .map(|(name, description, source_name)| match description.as_str() {
+            "" => format!("{name} (required by {source_name})"),
+            _ => format!("{name} ({description}, required by {source_name})"),
+        })
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        return Err(format!("missing required variables: {}", missing.join("; ")));
+    }
+
+    Ok(VariableSource {
+        name: sources.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", "),
         definitions: all_defs,
+        overrides: HashSet::new(),
+        finals: HashSet::new(),
+        required: HashMap::new(),
         mutations: sources.iter().flat_map(|s| s.mutations.clone()).collect(),
+        imports: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(yaml: &str) -> io::Result<ParsedDefs> {
+        parse_defs(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn error(yaml: &str) -> String {
+        parsed(yaml).err().expect("should be rejected").to_string()
+    }
+
+    #[test]
+    fn flattens_nested_definitions() {
+        let defs = parsed("db/:\n  host: x\n  pool/:\n    size: 5\nplain: 1").unwrap();
+        let mut names = defs.definitions.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["db/host", "db/pool/size", "plain"]);
+        assert_eq!(defs.definitions["db/pool/size"], Value::from(5));
+    }
+
+    #[test]
+    fn keeps_markers_when_nested() {
+        let defs = parsed("db/:\n  host: !override x\n  port: !final 5432\n  password: !required \"the password\"").unwrap();
+        assert!(defs.overrides.contains("db/host"));
+        assert!(defs.finals.contains("db/port"));
+        assert_eq!(defs.required["db/password"], "the password");
+        assert!(!defs.definitions.contains_key("db/password"));
+    }
+
+    #[test]
+    fn rejects_nested_and_flat_definitions_of_the_same_name() {
+        assert_eq!(
+            error("db/host: x\ndb/:\n  host: y"),
+            "Variable \"db/host\" is defined more than once (\"db/host\" and nested under \"db/\")"
+        );
+        assert_eq!(
+            error("db/:\n  host: y\ndb/host: x"),
+            "Variable \"db/host\" is defined more than once (nested under \"db/\" and \"db/host\")"
+        );
+    }
+
+    #[test]
+    fn rejects_the_same_name_nested_twice() {
+        assert_eq!(
+            error("db/:\n  pool/size: 1\ndb/pool/:\n  size: 2"),
+            "Variable \"db/pool/size\" is defined more than once (nested under \"db/\" and nested under \"db/pool/\")"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_group_names() {
+        assert_eq!(error("a//:\n  b: 1"), "Invalid group name \"a//\" in variable definitions");
+        assert_eq!(error("/:\n  b: 1"), "Invalid group name \"/\" in variable definitions");
+        assert_eq!(error("a/:\n  /:\n    b: 1"), "Invalid group name \"a//\" in variable definitions");
+    }
+
+    fn source(name: &str, yaml: &str) -> VariableSource {
+        let defs = parsed(yaml).unwrap();
+        VariableSource {
+            name: name.to_string(),
+            definitions: defs.definitions,
+            overrides: defs.overrides,
+            finals: defs.finals,
+            required: defs.required,
+            mutations: vec![],
+            imports: vec![],
+        }
+    }
+
+    #[test]
+    fn required_variables_must_be_defined() {
+        let common = source("common", "db/password: !required \"the password\"\nother: 1");
+        assert_eq!(
+            combine("prod", vec![&common]).err().unwrap(),
+            "missing required variables: db/password (the password, required by common)"
+        );
+        let prod = source("prod", "db/password: secret");
+        assert!(combine("prod", vec![&common, &prod]).is_ok());
+    }
+
+    #[test]
+    fn required_variables_can_be_defined_by_wildcards() {
+        let common = source("common", "db/password: !required \"\"");
+        let prod = source("prod", "db/*: \"(( runtime/db/* ))\"");
+        assert!(combine("prod", vec![&common, &prod]).is_ok());
+    }
+
+    #[test]
+    fn environment_name_is_defined() {
+        let common = source("common", "environment/name: !required \"\"");
+        let combined = combine("prod", vec![&common]).unwrap();
+        assert_eq!(combined.definitions["environment/name"], Value::from("prod"));
+    }
+
+    #[test]
+    fn rejects_groups_which_arent_mappings() {
+        assert_eq!(
+            error("db/: x"),
+            "\"db/\" ends with a slash, so must be a mapping of nested definitions"
+        );
+    }
+
+    // what `load_with_imports` finds for each of the given files, as (name, imports, definitions)
+    fn with_imports(name: &str, files: &[(&str, &[&str], &str)]) -> io::Result<Vec<String>> {
+        let mut load = |name: &str| match files.iter().find(|(file, _, _)| *file == name) {
+            Some((_, imports, yaml)) => {
+                let mut source = source(name, yaml);
+                source.imports = imports.iter().map(|import| import.to_string()).collect();
+                Ok(Rc::new(source))
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no file {name}"))),
+        };
+        let mut sources = vec![];
+        load_with_imports(name, &mut load, &mut vec![], &mut sources)?;
+        Ok(sources.into_iter().map(|(name, _)| name).collect())
+    }
+
+    #[test]
+    fn imports_come_first() {
+        let files: &[(&str, &[&str], &str)] = &[
+            ("prod", &["common", "region/eu"], "a: 1"),
+            ("region/eu", &["common", "eu"], "a: 2"),
+            ("common", &[], "a: 3"),
+            ("eu", &[], "a: 4"),
+        ];
+        assert_eq!(with_imports("prod", files).unwrap(), ["common", "eu", "region/eu", "prod"]);
+        assert_eq!(with_imports("region/eu", files).unwrap(), ["common", "eu", "region/eu"]);
+    }
+
+    #[test]
+    fn rejects_import_cycles() {
+        let files: &[(&str, &[&str], &str)] = &[("a", &["b"], ""), ("b", &["c"], ""), ("c", &["a"], "")];
+        assert_eq!(
+            with_imports("a", files).unwrap_err().to_string(),
+            "Variable files import each other: a -> b -> c -> a"
+        );
+        let files: &[(&str, &[&str], &str)] = &[("a", &["a"], "")];
+        assert_eq!(with_imports("a", files).unwrap_err().to_string(), "Variable files import each other: a -> a");
+    }
+
+    #[test]
+    fn missing_imports() {
+        let files: &[(&str, &[&str], &str)] = &[("a", &["nope"], "")];
+        assert_eq!(with_imports("a", files).unwrap_err().to_string(), "no file nope");
+    }
+
+    fn overridden(sources: &[&VariableSource]) -> Vec<String> {
+        overrides(sources)
+            .iter()
+            .map(|o| {
+                let marked = if o.marked { " (marked)" } else { "" };
+                format!("{} from {} by {}{marked}", &o.name, &o.previous_source, &o.source)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_overrides() {
+        let common = source("common", "a: 1
+b: 1
+c: 1");
+        let eu = source("eu", "b: !override 2");
+        let prod = source("prod", "c: 3
+b: 3
+d: !override 3");
+        assert_eq!(
+            overridden(&[&common, &eu, &prod]),
+            ["b from common by eu (marked)", "b from eu by prod", "c from common by prod"]
+        );
+        assert!(overridden(&[&common]).is_empty());
+    }
+
+    #[test]
+    fn override_policies() {
+        let common = source("common", "a: 1
+b: 1");
+        let prod = source("prod", "a: !override 2
+b: 2");
+        for policy in [OverridePolicy::Silent, OverridePolicy::Report] {
+            assert!(check_overrides("prod", &[&common, &prod], policy).is_ok());
+        }
+        assert_eq!(
+            check_overrides("prod", &[&common, &prod], OverridePolicy::RequireMarker).unwrap_err().to_string(),
+            "Environment \"prod\" has overrides which aren't marked !override: \"b\" from common is overridden by prod"
+        );
+        let marked = source("prod", "a: !override 2
+b: !override 2
+c: !override 2");
+        assert!(check_overrides("prod", &[&common, &marked], OverridePolicy::RequireMarker).is_ok());
+    }
+
+    #[test]
+    fn final_variables_cant_be_redefined() {
+        let common = source("common", "tls/min_version: !final \"1.2\"\nother: 1");
+        let prod = source("prod", "other: 2\ntls/min_version: \"1.0\"");
+        assert_eq!(
+            combine("prod", vec![&common, &prod]).err().unwrap(),
+            "\"tls/min_version\" is final in common, so prod can't redefine \"tls/min_version\""
+        );
+        // (other variables can still be overridden)
+        let prod = source("prod", "other: 2");
+        assert_eq!(combine("prod", vec![&common, &prod]).unwrap().definitions["other"], Value::from(2));
+    }
+
+    #[test]
+    fn final_wildcards_lock_everything_under_them() {
+        let common = source("common", "audit/*: !final true");
+        let prod = source("prod", "audit/log/enabled: false");
+        assert_eq!(
+            combine("prod", vec![&common, &prod]).err().unwrap(),
+            "\"audit/*\" is final in common, so prod can't redefine \"audit/log/enabled\""
+        );
+        let prod = source("prod", "auditing: false");
+        assert!(combine("prod", vec![&common, &prod]).is_ok());
+    }
+
+    #[test]
+    fn locking() {
+        assert!(locks("a/b", "a/b"));
+        assert!(!locks("a/b", "a/b/c"));
+        assert!(locks("a/*", "a/*"));
+        assert!(locks("a/*", "a/b/c"));
+        assert!(!locks("a/*", "ab"));
+        assert!(!locks("a*", "ab"));
     }
 }
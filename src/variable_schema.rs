@@ -0,0 +1,318 @@
+use crate::filters::type_name;
+use crate::processing::{expanded_definition, find_definition, Environment};
+use crate::text_templates::has_placeholders;
+use crate::variable_definitions::{invalid, string_value};
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// Types for variables, declared in `configuration/schema.yml` by name (or `name/*` for everything under `name/`):
+//
+// db/host: hostname
+// db/port: port
+// db/pool_size: {type: integer, min: 1, max: 100}
+// log/level: {enum: [debug, info, warn]}
+// app/slug: {regex: "[a-z][a-z0-9-]*"}
+//
+// Every environment's variables are checked against it (once expanded) before anything is compiled.
+const SCHEMA_FILE: &str = "configuration/schema.yml";
+
+#[derive(Debug)]
+pub(crate) enum VariableType {
+    String,
+    Integer { min: Option<i64>, max: Option<i64> },
+    Port,
+    Url,
+    Hostname,
+    Boolean,
+    Enum(Vec<Value>),
+    // a string which the whole of matches
+    Regex(Regex),
+}
+
+pub(crate) type VariableSchema = HashMap<String, VariableType>;
+
+fn integer_option(name: &str, options: &Mapping, option: &str) -> io::Result<Option<i64>> {
+    match options.get(option) {
+        None => Ok(None),
+        Some(Value::Number(n)) if n.is_i64() => Ok(n.as_i64()),
+        Some(other) => Err(invalid(format!("\"{option}\" for \"{name}\" must be an integer, got {other:?}"))),
+    }
+}
+
+fn parse_type(name: &str, declaration: &Value) -> io::Result<VariableType> {
+    let (type_name, options) = match declaration {
+        Value::String(type_name) => (type_name.as_str(), Mapping::new()),
+        Value::Mapping(options) => {
+            if let Some(values) = options.get("enum") {
+                return match values {
+                    Value::Sequence(values) if !values.is_empty() => Ok(VariableType::Enum(values.clone())),
+                    _ => Err(invalid(format!("\"enum\" for \"{name}\" must be a list of values"))),
+                };
+            }
+            if let Some(pattern) = options.get("regex") {
+                let pattern = string_value(pattern)
+                    .ok_or_else(|| invalid(format!("\"regex\" for \"{name}\" must be a string")))?;
+                return Regex::new(&format!("^(?:{pattern})$"))
+                    .map(VariableType::Regex)
+                    .map_err(|err| invalid(format!("Invalid regex for \"{name}\": {err}")));
+            }
+            let type_name = options
+                .get("type")
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid(format!("\"{name}\" needs a type, an enum or a regex")))?;
+            (type_name, options.clone())
+        }
+        other => return Err(invalid(format!("Invalid declaration for \"{name}\": {other:?}"))),
+    };
+    match type_name {
+        "string" => Ok(VariableType::String),
+        "integer" => Ok(VariableType::Integer {
+            min: integer_option(name, &options, "min")?,
+            max: integer_option(name, &options, "max")?,
+        }),
+        "port" => Ok(VariableType::Port),
+        "url" => Ok(VariableType::Url),
+        "hostname" => Ok(VariableType::Hostname),
+        "boolean" => Ok(VariableType::Boolean),
+        _ => Err(invalid(format!(
+            "Unknown type \"{type_name}\" for \"{name}\" (expected string, integer, port, url, hostname or boolean)"
+        ))),
+    }
+}
+
+pub(crate) fn load_schema() -> io::Result<VariableSchema> {
+    if !Path::new(SCHEMA_FILE).exists() {
+        return Ok(VariableSchema::new());
+    }
+    let declarations: Mapping = serde_yaml::from_reader(File::open(SCHEMA_FILE)?)
+        .map_err(|err| invalid(format!("Invalid YAML ({err}), in {SCHEMA_FILE}")))?;
+    let in_file = |err: io::Error| invalid(format!("{err}, in {SCHEMA_FILE}"));
+    let mut schema = VariableSchema::with_capacity(declarations.len());
+    for (name, declaration) in &declarations {
+        let name = string_value(name)
+            .ok_or_else(|| invalid(format!("Non-string variable name {name:?}, in {SCHEMA_FILE}")))?;
+        let variable_type = parse_type(&name, declaration).map_err(in_file)?;
+        schema.insert(name, variable_type);
+    }
+    Ok(schema)
+}
+
+// RFC 1123, without a trailing dot
+fn is_hostname(s: &str) -> bool {
+    s.len() <= 253
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn check(variable_type: &VariableType, value: &Value) -> Result<(), String> {
+    let expected = match (variable_type, value) {
+        (VariableType::String, Value::String(_)) => return Ok(()),
+        (VariableType::String, _) => "a string".to_string(),
+        (VariableType::Integer { min, max }, Value::Number(n)) if n.is_i64() => {
+            let n = n.as_i64().unwrap();
+            if min.is_some_and(|min| n < min) || max.is_some_and(|max| n > max) {
+                let bound = |b: &Option<i64>| b.map(|b| b.to_string()).unwrap_or_default();
+                return Err(format!("{n} is out of range ({}..{})", bound(min), bound(max)));
+            }
+            return Ok(());
+        }
+        (VariableType::Integer { .. }, _) => "an integer".to_string(),
+        (VariableType::Port, Value::Number(n)) if n.as_u64().is_some_and(|n| (1..=65535).contains(&n)) => {
+            return Ok(())
+        }
+        (VariableType::Port, _) => "a port number (1-65535)".to_string(),
+        (VariableType::Url, Value::String(s)) => {
+            return url::Url::parse(s)
+                .map(|_| ())
+                .map_err(|err| format!("\"{s}\" isn't a valid URL ({err})"))
+        }
+        (VariableType::Url, _) => "a URL".to_string(),
+        (VariableType::Hostname, Value::String(s)) if is_hostname(s) => return Ok(()),
+        (VariableType::Hostname, _) => "a hostname".to_string(),
+        (VariableType::Boolean, Value::Bool(_)) => return Ok(()),
+        (VariableType::Boolean, _) => "a boolean".to_string(),
+        (VariableType::Enum(values), value) if values.contains(value) => return Ok(()),
+        (VariableType::Enum(values), _) => format!(
+            "one of {}",
+            values
+                .iter()
+                .map(|v| serde_json::to_string(v).unwrap_or_else(|_| format!("{v:?}")))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        (VariableType::Regex(regex), Value::String(s)) if regex.is_match(s) => return Ok(()),
+        (VariableType::Regex(regex), _) => format!("a string matching {}", regex.as_str()),
+    };
+    let got = match value {
+        Value::String(s) => format!("\"{s}\""),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        other => format!("a {}", type_name(other)),
+    };
+    Err(format!("expected {expected}, got {got}"))
+}
+
+// Runtime values are left as placeholders (e.g. "http://(( runtime/host ))"), so can't be checked yet
+fn has_runtime_lookups(value: &Value) -> bool {
+    match value {
+        Value::String(s) => has_placeholders(s),
+        Value::Sequence(seq) => seq.iter().any(has_runtime_lookups),
+        Value::Mapping(m) => m.values().any(has_runtime_lookups),
+        _ => false,
+    }
+}
+
+// Checks all of an environment's variables which the schema has a type for
+pub(crate) fn validate(schema: &VariableSchema, environment: &Environment) -> Result<(), String> {
+    let mut names = environment.definitions.definitions.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut errors = vec![];
+    for name in names {
+        let Some((_, variable_type)) = find_definition(schema, name) else {
+            continue;
+        };
+        let value = expanded_definition(name, environment).unwrap();
+        if has_runtime_lookups(&value) {
+            continue;
+        }
+        if let Err(err) = check(variable_type, &value) {
+            errors.push(format!("{name}: {err}"));
+        }
+    }
+    // (checking isn't using)
+    environment.used.take();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("invalid variables: {}", errors.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::tests::environment;
+
+    fn schema(yaml: &str) -> VariableSchema {
+        let declarations: Mapping = serde_yaml::from_str(yaml).unwrap();
+        declarations
+            .iter()
+            .map(|(name, declaration)| {
+                let name = string_value(name).unwrap();
+                let variable_type = parse_type(&name, declaration).unwrap();
+                (name, variable_type)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checking_isnt_using() {
+        let environment = environment("{a: (( b )), b: 1}");
+        assert_eq!(validate(&schema("{a: integer}"), &environment), Ok(()));
+        assert!(environment.used.borrow().is_empty());
+    }
+
+    fn checked(declaration: &str, value: &str) -> Result<(), String> {
+        let variable_type = parse_type("x", &serde_yaml::from_str(declaration).unwrap()).unwrap();
+        check(&variable_type, &serde_yaml::from_str(value).unwrap())
+    }
+
+    #[test]
+    fn rejects_invalid_declarations() {
+        let error = |declaration: &str| {
+            parse_type("x", &serde_yaml::from_str(declaration).unwrap()).unwrap_err().to_string()
+        };
+        assert_eq!(
+            error("number"),
+            "Unknown type \"number\" for \"x\" (expected string, integer, port, url, hostname or boolean)"
+        );
+        assert_eq!(error("{type: integer, min: a}"), "\"min\" for \"x\" must be an integer, got String(\"a\")");
+        assert_eq!(error("{enum: []}"), "\"enum\" for \"x\" must be a list of values");
+        assert!(error("{regex: '('}").starts_with("Invalid regex for \"x\""));
+        assert_eq!(error("{min: 1}"), "\"x\" needs a type, an enum or a regex");
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(checked("port", "8080"), Ok(()));
+        assert_eq!(checked("port", "65535"), Ok(()));
+        for value in ["0", "65536", "-1", "80.5"] {
+            assert_eq!(checked("port", value), Err(format!("expected a port number (1-65535), got {value}")));
+        }
+        assert_eq!(checked("port", "'80'"), Err("expected a port number (1-65535), got \"80\"".to_string()));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(checked("integer", "-5"), Ok(()));
+        assert_eq!(checked("{type: integer, min: 1, max: 100}", "1"), Ok(()));
+        assert_eq!(checked("{type: integer, min: 1, max: 100}", "100"), Ok(()));
+        assert_eq!(checked("{type: integer, min: 1, max: 100}", "0"), Err("0 is out of range (1..100)".to_string()));
+        assert_eq!(checked("{type: integer, min: 1}", "0"), Err("0 is out of range (1..)".to_string()));
+        assert_eq!(checked("{type: integer, max: 100}", "101"), Err("101 is out of range (..100)".to_string()));
+        assert_eq!(checked("integer", "1.5"), Err("expected an integer, got 1.5".to_string()));
+        assert_eq!(checked("integer", "[1]"), Err("expected an integer, got a sequence".to_string()));
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(checked("url", "https://example.com/x?y=1"), Ok(()));
+        assert_eq!(
+            checked("url", "example.com"),
+            Err("\"example.com\" isn't a valid URL (relative URL without a base)".to_string())
+        );
+        assert_eq!(checked("url", "1"), Err("expected a URL, got 1".to_string()));
+    }
+
+    #[test]
+    fn hostnames() {
+        for hostname in ["localhost", "db-1.internal", "a.b.c", &"a".repeat(63)] {
+            assert_eq!(checked("hostname", hostname), Ok(()), "{hostname}");
+        }
+        for hostname in ["-db", "db-", "a..b", "a.", "db_1", "höst", &"a".repeat(64), "''"] {
+            assert!(checked("hostname", hostname).is_err(), "{hostname}");
+        }
+    }
+
+    #[test]
+    fn enums() {
+        assert_eq!(checked("{enum: [debug, info, 1]}", "info"), Ok(()));
+        assert_eq!(checked("{enum: [debug, info, 1]}", "1"), Ok(()));
+        assert_eq!(
+            checked("{enum: [debug, info, 1]}", "warn"),
+            Err("expected one of \"debug\", \"info\", 1, got \"warn\"".to_string())
+        );
+    }
+
+    #[test]
+    fn regexes_match_the_whole_string() {
+        let declaration = "{regex: '[a-z]+|[0-9]+'}";
+        assert_eq!(checked(declaration, "abc"), Ok(()));
+        assert_eq!(checked(declaration, "'123'"), Ok(()));
+        for value in ["abc1", "1abc", "a-b"] {
+            assert_eq!(
+                checked(declaration, value),
+                Err(format!("expected a string matching ^(?:[a-z]+|[0-9]+)$, got \"{value}\""))
+            );
+        }
+    }
+
+    #[test]
+    fn skips_runtime_values() {
+        let environment = environment("{a/url: \"http://(( runtime/host )):80\", a/port: (( runtime/port )), b: x}");
+        assert_eq!(validate(&schema("{a/url: port, a/port: port}"), &environment), Ok(()));
+        assert_eq!(
+            validate(&schema("{a/*: port, b: port}"), &environment),
+            Err("invalid variables: b: expected a port number (1-65535), got \"x\"".to_string())
+        );
+    }
+}